@@ -3,46 +3,43 @@ use std::fs::File;
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::{BufReader, Read, Take};
 use std::str::FromStr;
+use std::sync::Arc;
 use chrono::DateTime;
 use headers_accept::Accept;
 use varnish::vcl::backend::{Serve, Transfer};
 use varnish::vcl::ctx::Ctx;
 use varnish::vcl::http::HTTP;
 use crate::cache::{Cache, FetchResult};
-use crate::config::Config;
 use crate::error::Error;
 
 pub struct FileBackend {
-    config: Config,
-    cache: Cache,
+    cache: Arc<Cache>,
 }
 
 impl FileBackend {
-    pub fn new(config: Config, cache: Cache) -> Self {
+    pub fn new(cache: Arc<Cache>) -> Self {
         FileBackend {
-            config,
             cache,
         }
     }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
 }
 
 impl FileBackend {
     fn get_data(&self, ctx: &mut Ctx) -> Result<Option<FileTransfer>, Error> {
+        let config = self.cache.config()?;
         let bereq = ctx.http_bereq.as_ref().ok_or_else(|| Error::new("Failed to get request data"))?;
         let bereq_method = bereq.method().unwrap_or("");
-        let bereq_url = urlencoding::decode(bereq.url().ok_or_else(|| Error::new("Failed to get URL"))?)?;
+        let bereq_url = bereq.url().ok_or_else(|| Error::new("Failed to get URL"))?;
         let beresp = ctx.http_beresp.as_mut().ok_or_else(|| Error::new("Failed to get response"))?;
         let mut transfer = None;
 
-        let pattern = self.config.url_regex.as_ref().expect("Badly initialized config");
-
-        if let Some(captures) = pattern.captures(bereq_url.as_ref()) {
-            if !self.config.sizes.get(&captures["size"]).map_or(false, |p| p.matches(&captures["path"])) {
-                respond!(ctx, 404);
-            }
-
+        if let Some(image) = config.parse_url(bereq_url)? {
             let accept = self.parse_accept_header(bereq);
-            let Some(result) = self.cache.get(&captures["path"], &captures["size"], accept)? else {
+            let Some(result) = self.cache.get(&image.path, &image.size, image.root.as_deref(), accept)? else {
                 respond!(ctx, 404);
             };
 
@@ -56,7 +53,7 @@ impl FileBackend {
             beresp.set_header("Last-Modified", &result.last_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string())?;
             beresp.set_header("Content-Length", &result.data.size().to_string())?;
             beresp.set_header("Content-Type", result.mime)?;
-            beresp.set_header("Vary", "Accept")?;
+            beresp.set_header("Vary", config.vary_header.as_deref().unwrap_or("Accept"))?;
             beresp.set_header("Cache-Control", if result.is_optimized {
                 "public, max-age=31536000, immutable"
             } else {
@@ -79,8 +76,8 @@ impl FileBackend {
         Ok(transfer)
     }
 
-    fn parse_accept_header(&self, bereq: &HTTP) -> Option<Accept> {
-        match bereq.header("accept") {
+    pub fn parse_accept_header(&self, req: &HTTP) -> Option<Accept> {
+        match req.header("accept") {
             Some(accept) if accept.trim() != "*/*" => Accept::from_str(accept).ok(),
             _ => None
         }
@@ -0,0 +1,102 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Admission controller limiting the memory used by concurrent jobs, jobs wait until
+/// enough of the budget is released. A job larger than the whole budget is still
+/// admitted once it is the only one running, otherwise it would never run
+#[derive(Clone)]
+pub struct MemoryBudget {
+    budget: Option<u64>,
+    used: Arc<(Mutex<u64>, Condvar)>,
+}
+
+pub struct Reservation {
+    amount: u64,
+    used: Arc<(Mutex<u64>, Condvar)>,
+}
+
+impl MemoryBudget {
+    pub fn new(budget: Option<u64>) -> Self {
+        MemoryBudget {
+            budget,
+            used: Arc::new((Mutex::new(0), Condvar::new())),
+        }
+    }
+
+    pub fn reserve(&self, amount: u64) -> Reservation {
+        let (lock, condvar) = &*self.used;
+        let mut used = lock.lock().unwrap();
+
+        if let Some(budget) = self.budget {
+            while *used > 0 && *used + amount > budget {
+                used = condvar.wait(used).unwrap();
+            }
+        }
+
+        *used += amount;
+
+        Reservation {
+            amount,
+            used: self.used.clone(),
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        *self.used.0.lock().unwrap()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.used;
+        if let Ok(mut used) = lock.lock() {
+            *used -= self.amount;
+        }
+
+        condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_reservation_is_released() {
+        let budget = MemoryBudget::new(Some(100));
+
+        let reservation = budget.reserve(60);
+        assert_eq!(budget.used(), 60);
+
+        drop(reservation);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_oversized_job_runs_alone() {
+        let budget = MemoryBudget::new(Some(100));
+
+        let _reservation = budget.reserve(500);
+        assert_eq!(budget.used(), 500);
+    }
+
+    #[test]
+    fn test_waits_for_budget() {
+        let budget = MemoryBudget::new(Some(100));
+        let first = budget.reserve(60);
+
+        let (tx, rx) = mpsc::channel();
+        let thread_budget = budget.clone();
+        thread::spawn(move || {
+            let _reservation = thread_budget.reserve(60);
+            tx.send(()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        drop(first);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
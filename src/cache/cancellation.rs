@@ -0,0 +1,98 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+struct State {
+    cancelled: AtomicBool,
+    threads: Mutex<usize>,
+    condvar: Condvar,
+}
+
+/// Shared by a cache and its background threads, threads check it between units of
+/// work and stay registered until they exit so a shutdown can wait for them
+#[derive(Clone)]
+pub struct Cancellation {
+    state: Arc<State>,
+}
+
+/// Keeps a thread counted as running until dropped
+pub struct ThreadGuard {
+    state: Arc<State>,
+}
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Cancellation {
+            state: Arc::new(State {
+                cancelled: AtomicBool::new(false),
+                threads: Mutex::new(0),
+                condvar: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Must be called before spawning the thread, otherwise a shutdown could miss it
+    pub fn register(&self) -> ThreadGuard {
+        if let Ok(mut threads) = self.state.threads.lock() {
+            *threads += 1;
+        }
+
+        ThreadGuard {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Waits for the registered threads to exit, returns false on timeout
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let Ok(threads) = self.state.threads.lock() else {
+            return false;
+        };
+
+        self.state.condvar.wait_timeout_while(threads, timeout, |threads| *threads > 0)
+            .is_ok_and(|(_, result)| !result.timed_out())
+    }
+}
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        if let Ok(mut threads) = self.state.threads.lock() {
+            *threads -= 1;
+        }
+
+        self.state.condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_wait_for_threads() {
+        let cancellation = Cancellation::new();
+        let guard = cancellation.register();
+        let thread_cancellation = cancellation.clone();
+
+        let handle = thread::spawn(move || {
+            let _guard = guard;
+            while !thread_cancellation.is_cancelled() {
+                thread::sleep(Duration::from_millis(5));
+            }
+        });
+
+        assert!(!cancellation.wait(Duration::from_millis(20)));
+
+        cancellation.cancel();
+        assert!(cancellation.wait(Duration::from_secs(5)));
+        handle.join().unwrap();
+    }
+}
@@ -1,49 +1,160 @@
-use std::path::PathBuf;
-use std::sync::mpsc::Receiver;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 use std::thread;
 use std::time::Duration;
+use image::{DynamicImage, ImageFormat};
 use rusty_pool::ThreadPool;
-use crate::cache::CacheData;
-use crate::config::{Config, Extension};
+use crate::cache::{Cache, CacheData, CacheImage};
+use crate::cache::cancellation::Cancellation;
+use crate::cache::budget::MemoryBudget;
+use crate::cache::manifest;
+use crate::cache::manifest::ManifestEntry;
+use crate::cache::scheduler::Scheduler;
+use crate::config::{Config, Extension, SharedConfig, Size};
 use crate::error::Error;
 use crate::images;
 use crate::images::OptimizationConfig;
 
+/// Every variant of an image to optimize, the source is only decoded once
+/// and then resized and encoded to all the requested sizes and extensions
 pub struct OptimizeImage {
     pub image_id: String,
-    pub size: String,
-    pub extension: Extension,
+    pub variants: HashMap<String, Vec<Extension>>, //associating sizes to the extensions to encode
 }
 
-pub fn spawn(config: Config, data: CacheData, rx: Receiver<OptimizeImage>) {
-    let threads = config.pre_optimizer_threads.unwrap_or(1);
+impl OptimizeImage {
+    pub fn new(image_id: &str, size: &str, extensions: Vec<Extension>) -> Self {
+        OptimizeImage {
+            image_id: image_id.to_owned(),
+            variants: HashMap::from([(size.to_owned(), extensions)]),
+        }
+    }
+}
+
+/// The number of threads and the memory budget are read once, other settings
+/// are read for each job so reloaded configurations apply to the next jobs
+pub fn spawn(config: SharedConfig, data: CacheData, scheduler: Scheduler, cancellation: Cancellation) {
+    let (threads, max_encode_memory) = {
+        let config = config.read().expect("Failed to start file saver thread");
+        (config.pre_optimizer_threads.unwrap_or(1), config.max_encode_memory)
+    };
+
     let pool = ThreadPool::new(0, threads, Duration::from_secs(60));
+    let budget = MemoryBudget::new(max_encode_memory);
+    let guard = cancellation.register();
 
     thread::spawn(move || {
-        while let Ok(image) = rx.recv() {
-            let task_config = config.clone();
+        let _guard = guard;
+
+        //stops once the scheduler is closed
+        while let Ok((image, running)) = scheduler.next() {
+            //read before the configuration so a purge following a reload always drops the results
+            let generations = match data.read() {
+                Ok(lock) => lock.get(&image.image_id).map(|cache| {
+                    image.variants.keys()
+                        .map(|size| (size.clone(), cache.generation(size)))
+                        .collect::<HashMap<String, u64>>()
+                }),
+                Err(_) => break,
+            };
+            let Some(generations) = generations else {
+                debug!("Image {} was deleted before being optimized", image.image_id);
+                continue;
+            };
+            let Ok(task_config) = config.read().map(|config| config.clone()) else {
+                break;
+            };
             let task_data = data.clone();
+            let task_budget = budget.clone();
+            let task_cancellation = cancellation.clone();
+            let task_guard = cancellation.register();
 
             pool.execute(move || {
+                let _guard = task_guard;
                 let image_id = image.image_id.clone();
-                if let Err(error) = save_image(task_config, task_data, image) {
+
+                //wait for enough memory to be available before decoding the source
+                let estimate = estimate_memory(&task_config, &task_data, &image).unwrap_or(0);
+                let _reservation = task_budget.reserve(estimate);
+
+                //the cache was shut down while waiting
+                if task_cancellation.is_cancelled() {
+                    return;
+                }
+
+                if let Err(error) = save_images(task_config, task_data, image, &generations) {
                     error!("Failed to save optimized images {}: {}", image_id, error.to_string());
                 }
+
+                drop(running);
             })
         }
+
+        //idle workers exit right away instead of after their keep alive
+        pool.shutdown();
     });
 }
 
-fn save_image(config: Config, cache: CacheData, image: OptimizeImage) -> Result<(), Error> {
-    let mut path = PathBuf::from(&config.cache_directory);
-    path.push(&image.size);
-    path.push(&image.image_id);
-    path.set_extension(image.extension.extensions().first().expect("Failed to get extension"));
+/// Image a job saves the variants of, `generations` being the ones of its sizes when the job started
+struct Job<'a> {
+    cache: &'a CacheData,
+    image_id: &'a str,
+    generations: &'a HashMap<String, u64>,
+}
 
-    let Some(size) = config.sizes.get(&image.size) else {
-        return Error::err(format!("Unknown image size {}", image.size))
+impl Job<'_> {
+    /// False once the variants of the size were purged, the results of the job for it are stale
+    fn is_current(&self, size: &str) -> Result<bool, Error> {
+        Ok(self.cache.read()?.get(self.image_id).is_some_and(|cache_image| self.matches(cache_image, size)))
+    }
+
+    /// Same check for callers already holding the lock
+    fn matches(&self, cache_image: &CacheImage, size: &str) -> bool {
+        cache_image.generation(size) == self.generations.get(size).copied().unwrap_or(0)
+    }
+}
+
+/// Bytes per pixel needed by the encoders on top of the image itself, AVIF being the most
+/// demanding with its YUV planes and encoder state
+const ENCODER_BYTES_PER_PIXEL: u64 = 16;
+
+/// Rough peak memory of a job: the decoded source, the resized intermediates that are
+/// kept to downscale the smaller sizes from, and the encoder of the largest size
+fn estimate_memory(config: &Config, cache: &CacheData, image: &OptimizeImage) -> Result<u64, Error> {
+    let base_image_path = {
+        let lock = cache.read()?;
+        let data = lock.get(&image.image_id).ok_or(Error::new("Image not found"))?;
+
+        data.base_image_path.clone()
+    };
+
+    let (source_width, source_height) = if images::svg::is_svg(&base_image_path) {
+        (0, 0)
+    } else {
+        images::dimensions(&base_image_path)?
     };
 
+    let resized = image.variants.keys()
+        .filter_map(|size_name| config.sizes.get(size_name))
+        .map(|size| {
+            let (width, height) = images::fit_dimensions(source_width.max(1), source_height.max(1), size.width, size.height);
+            width as u64 * height as u64
+        })
+        .collect::<Vec<u64>>();
+
+    let source = source_width as u64 * source_height as u64 * 4;
+    let intermediates = resized.iter().sum::<u64>() * 4;
+    let encoder = resized.iter().max().unwrap_or(&0) * ENCODER_BYTES_PER_PIXEL;
+
+    Ok(source + intermediates + encoder)
+}
+
+/// The results of a size are dropped once its variants are purged, `generations` being
+/// the ones of the sizes when the job started
+fn save_images(config: Arc<Config>, cache: CacheData, image: OptimizeImage, generations: &HashMap<String, u64>) -> Result<(), Error> {
     let base_image_path = {
         let lock = cache.read()?;
         let data = lock.get(&image.image_id).ok_or(Error::new("Image not found"))?;
@@ -51,17 +162,348 @@ fn save_image(config: Config, cache: CacheData, image: OptimizeImage) -> Result<
         data.base_image_path.clone()
     };
 
-    let optimization_config = OptimizationConfig::new(size, image.extension, false);
-    let optimized = images::read(&base_image_path)?;
-    let optimized = images::resize(&optimized, size.width, size.height);
-    let optimized = images::optimize(&optimized, optimization_config)?;
+    let mut sizes = image.variants.iter()
+        .map(|(size_name, extensions)| match config.sizes.get(size_name) {
+            Some(size) => Ok((size_name, size, extensions)),
+            None => Error::err(format!("Unknown image size {}", size_name)),
+        })
+        .collect::<Result<Vec<(&String, &Size, &Vec<Extension>)>, Error>>()?;
+
+    //largest sizes first so smaller ones can be downscaled from them
+    sizes.sort_by_key(|(_, size, _)| Reverse(size.width as u64 * size.height as u64));
+
+    let is_svg = images::svg::is_svg(&base_image_path);
+    let source = if is_svg {
+        config.limits.as_ref()
+            .map_or(Ok(()), |limits| images::check_limits(&base_image_path, limits))
+            .map(|_| None)
+    } else {
+        images::dimensions(&base_image_path)
+            .map(|(width, height)| decode_bounds(width, height, &sizes))
+            .and_then(|bounds| images::read(&base_image_path, bounds, config.limits.as_ref()))
+            .map(Some)
+    };
+
+    //not a failure, the source is served as is or rejected from now on
+    if let Err(Error::LimitExceeded(reason)) = &source {
+        warn!("Source image {} exceeds the configured limits: {}", image.image_id, reason);
+
+        if let Some(cache_image) = cache.write()?.get_mut(&image.image_id) {
+            cache_image.over_limit = OnceLock::from(true);
+        }
+
+        return Ok(());
+    }
+
+    let mut intermediates = source?.into_iter().collect::<Vec<DynamicImage>>();
+    let job = Job { cache: &cache, image_id: &image.image_id, generations };
+
+    //classified once from the source, resampling adds interpolated colors to flat graphics
+    let mut is_graphic = None;
+
+    for (size_name, size, extensions) in sizes {
+        if !job.is_current(size_name)? {
+            debug!("Variants of {} of size {} were purged while optimizing it", image.image_id, size_name);
+            continue;
+        }
+
+        let resized = if is_svg {
+            images::svg::rasterize(&base_image_path, size.width, size.height)?
+        } else {
+            let source = &intermediates[0];
+            let (width, height) = images::fit_dimensions(source.width(), source.height(), size.width, size.height);
+
+            //the smallest intermediate that is still large enough, to avoid upscaling
+            let intermediate = intermediates.iter()
+                .rev()
+                .find(|intermediate| intermediate.width() >= width && intermediate.height() >= height)
+                .unwrap_or(source);
+
+            images::resize(intermediate, size.width, size.height, size.linear_resize.unwrap_or(false))
+        };
+
+        let classified = intermediates.first().unwrap_or(&resized);
+        let lossless = size.is_lossless(|| *is_graphic.get_or_insert_with(|| images::is_graphic(classified)));
 
-    images::write(&path, &optimized.data(), None)?;
+        //served instead of the optimized images that are not smaller than it
+        let source_length = if is_svg {
+            None
+        } else {
+            Some(save_resized_source(&config, &job, &base_image_path, size_name, size, &resized, lossless)?)
+        };
 
-    cache.write()?
-        .get_mut(&image.image_id)
-        .ok_or_else(|| Error::new("Failed to get a lock"))?
-        .add(image.size, image.extension, &path);
+        //queued before a reload that removed the extension
+        for &extension in extensions.iter().filter(|extension| config.extensions.contains(extension)) {
+            let result = if lossless && extension == Extension::AVIF {
+                //libavif can not encode RGB losslessly, the clients accepting AVIF get the lossless WEBP
+                save_image(&config, &job, size_name, extension, size.quality[extension as usize], Outcome::Skipped)
+            } else if source_length.is_some() && extension.image_format() == Cache::source_format(&base_image_path) {
+                //encoding the source format again would give the resized source back
+                save_image(&config, &job, size_name, extension, size.quality[extension as usize], Outcome::SourceFormat)
+            } else {
+                let optimization_config = OptimizationConfig::new(size, extension, lossless, false);
+
+                images::optimize(&resized, optimization_config, size.target).and_then(|optimized| {
+                    let data = optimized.image.data();
+                    let outcome = if source_length.is_none_or(|length| is_smaller(&config, data, length)) {
+                        Outcome::Smaller(data)
+                    } else {
+                        Outcome::NotSmaller(data)
+                    };
+
+                    save_image(&config, &job, size_name, extension, optimized.quality, outcome)
+                })
+            };
+
+            if let Err(error) = result {
+                error!("Failed to save optimized image {} {:?} of size {}: {}", image.image_id, extension, size_name, error);
+            }
+        }
+
+        if !is_svg {
+            intermediates.push(resized);
+        }
+    }
 
     Ok(())
 }
+
+/// Smallest bounds the source can be decoded to while still fitting every size, the boxes
+/// are not sorted by what the source fits in them when their aspect ratios differ
+fn decode_bounds(source_width: u32, source_height: u32, sizes: &[(&String, &Size, &Vec<Extension>)]) -> Option<(u32, u32)> {
+    sizes.iter()
+        .map(|(_, size, _)| images::fit_dimensions(source_width.max(1), source_height.max(1), size.width, size.height))
+        .reduce(|(max_width, max_height), (width, height)| (max_width.max(width), max_height.max(height)))
+}
+
+/// Encodes the source format at the size unless it already was, returns its length in bytes
+fn save_resized_source(config: &Config, job: &Job, base_image_path: &str, size_name: &str, size: &Size, resized: &DynamicImage, lossless: bool) -> Result<u64, Error> {
+    let path = Cache::resized_source_path(config, job.image_id, base_image_path, size_name);
+    if let Ok(metadata) = fs::metadata(&path) {
+        return Ok(metadata.len());
+    }
+
+    let data = match Extension::from_ext(extension_of(&path)) {
+        Some(extension) => images::optimize(resized, OptimizationConfig::new(size, extension, lossless, false), size.target)?.image.data().to_vec(),
+        None => images::to_png(resized)?,
+    };
+
+    //written under the lock, a purge waits for it and then deletes it
+    let lock = job.cache.read()?;
+    if lock.get(job.image_id).is_some_and(|cache_image| job.matches(cache_image, size_name)) {
+        images::write(&path, &data, None)?;
+    }
+
+    Ok(data.len() as u64)
+}
+
+fn extension_of(path: &Path) -> &str {
+    path.extension().and_then(|extension| extension.to_str()).unwrap_or(ImageFormat::Png.extensions_str()[0])
+}
+
+/// What is kept of a variant once encoded
+enum Outcome<'a> {
+    /// Served as is
+    Smaller(&'a [u8]),
+    /// The resized source is served instead, the optimized image only to the clients not accepting its format
+    NotSmaller(&'a [u8]),
+    /// The resized source is served
+    SourceFormat,
+    /// Not produced, the image is encoded losslessly and the format can not be
+    Skipped,
+}
+
+/// Nothing is saved when the variants of the image were purged since the job started
+fn save_image(config: &Config, job: &Job, size_name: &str, extension: Extension, quality: f32, outcome: Outcome) -> Result<(), Error> {
+    let mut path = PathBuf::from(&config.cache_directory);
+    path.push(size_name);
+    path.push(job.image_id);
+    path.set_extension(extension.extensions().first().expect("Failed to get extension"));
+
+    //held while writing so a purge can not happen between the check and the insertion
+    let mut lock = job.cache.write()?;
+    let cache_image = lock.get_mut(job.image_id).ok_or_else(|| Error::new("Failed to get a lock"))?;
+
+    if !job.matches(cache_image, size_name) {
+        debug!("Variants of {} of size {} were purged while optimizing it", job.image_id, size_name);
+        return Ok(());
+    }
+
+    let (data, use_source, skipped) = match outcome {
+        Outcome::Smaller(data) => (Some(data), false, false),
+        Outcome::NotSmaller(data) => (Some(data), true, false),
+        Outcome::SourceFormat => (None, true, false),
+        Outcome::Skipped => (None, false, true),
+    };
+
+    if let Some(data) = data {
+        images::write(&path, data, None)?;
+    }
+
+    if use_source {
+        debug!("Optimized image {} {:?} of size {} is not smaller than the source format", job.image_id, extension, size_name);
+    } else if skipped {
+        debug!("Image {} is encoded losslessly, {:?} is skipped for size {}", job.image_id, extension, size_name);
+    }
+
+    let entry = ManifestEntry {
+        image_id: job.image_id.to_owned(),
+        size: size_name.to_owned(),
+        extension,
+        quality,
+        use_source,
+        skipped,
+    };
+
+    let variant = (size_name.to_owned(), extension);
+
+    //an earlier entry flagging the variant would win over the optimized image on restart
+    if manifest::is_needed(config, &entry) || cache_image.use_source.contains(&variant) || cache_image.skipped.contains(&variant) {
+        manifest::append(config, &entry)?;
+    }
+
+    cache_image.qualities.insert(variant.clone(), quality);
+    if data.is_some() {
+        cache_image.add(size_name.to_owned(), extension, &path);
+    }
+
+    if use_source {
+        cache_image.use_source.insert(variant.clone());
+    } else {
+        cache_image.use_source.remove(&variant);
+    }
+
+    if skipped {
+        cache_image.skipped.insert(variant);
+    } else {
+        cache_image.skipped.remove(&variant);
+    }
+
+    Ok(())
+}
+
+/// Checks the optimized image saves at least `min_savings` percent over the source format
+/// encoded at the same size
+fn is_smaller(config: &Config, optimized: &[u8], source_length: u64) -> bool {
+    let min_savings = config.min_savings.unwrap_or(0.0) as f64;
+
+    (optimized.len() as f64) < source_length as f64 * (1.0 - min_savings / 100.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+    use crate::config::Compression;
+    use crate::utils;
+
+    #[test]
+    fn test_save_images_compares_resized_source() {
+        let mut config = Config {
+            extensions: vec![Extension::WEBP, Extension::JPEG],
+            cache_directory: utils::temp_dir("resized_source"),
+            ..Config::default()
+        };
+        config.sizes.get_mut("default").unwrap().width = 100;
+        config.sizes.get_mut("default").unwrap().height = 100;
+        let _ = fs::remove_dir_all(&config.cache_directory);
+
+        let base_image_path = concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/monitor.webp");
+        let cache = CacheData::new(RwLock::new(HashMap::from([(String::from("products/monitor"), CacheImage::new(base_image_path.to_owned()))])));
+        let image = OptimizeImage::new("products/monitor", "default", vec![Extension::WEBP, Extension::JPEG]);
+
+        save_images(Arc::new(config.clone()), cache.clone(), image, &HashMap::new()).unwrap();
+
+        let resized_source = Cache::resized_source_path(&config, "products/monitor", base_image_path, "default");
+        let (width, height) = images::dimensions(&resized_source).unwrap();
+        assert!(width <= 100 && height <= 100);
+
+        let lock = cache.read().unwrap();
+        let cache_image = &lock["products/monitor"];
+        //the source format is never compared to itself
+        assert!(cache_image.uses_source("default", Extension::WEBP));
+        assert!(cache_image.has("default", Extension::JPEG));
+    }
+
+    #[test]
+    fn test_save_images_skips_avif_when_lossless() {
+        let mut config = Config {
+            extensions: vec![Extension::AVIF, Extension::WEBP],
+            cache_directory: utils::temp_dir("skips_avif"),
+            ..Config::default()
+        };
+        config.sizes.get_mut("default").unwrap().compression = Some(Compression::Lossless);
+        let _ = fs::remove_dir_all(&config.cache_directory);
+
+        let base_image_path = concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/cutout.png");
+        let cache = CacheData::new(RwLock::new(HashMap::from([(String::from("products/cutout"), CacheImage::new(base_image_path.to_owned()))])));
+        let image = OptimizeImage::new("products/cutout", "default", vec![Extension::AVIF, Extension::WEBP]);
+
+        save_images(Arc::new(config.clone()), cache.clone(), image, &HashMap::new()).unwrap();
+
+        let lock = cache.read().unwrap();
+        let cache_image = &lock["products/cutout"];
+        assert!(cache_image.skips("default", Extension::AVIF));
+        assert!(cache_image.get("default", Extension::AVIF).is_none());
+        assert!(cache_image.has("default", Extension::WEBP) && !cache_image.skips("default", Extension::WEBP));
+
+        //not optimized again on restart
+        let manifest = manifest::load(&config);
+        assert!(manifest[&(String::from("products/cutout"), String::from("default"), Extension::AVIF)].skipped);
+    }
+
+    #[test]
+    fn test_save_images_drops_purged_results() {
+        let config = Config {
+            extensions: vec![Extension::WEBP],
+            cache_directory: utils::temp_dir("purged_results"),
+            ..Config::default()
+        };
+        let _ = fs::remove_dir_all(&config.cache_directory);
+
+        let base_image_path = concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/cutout.png");
+        let mut cache_image = CacheImage::new(base_image_path.to_owned());
+        cache_image.generation = 1;
+        let cache = CacheData::new(RwLock::new(HashMap::from([(String::from("products/cutout"), cache_image)])));
+
+        //started before the purge that bumped the generation
+        let image = OptimizeImage::new("products/cutout", "default", vec![Extension::WEBP]);
+        save_images(Arc::new(config.clone()), cache.clone(), image, &HashMap::new()).unwrap();
+
+        assert!(cache.read().unwrap()["products/cutout"].optimized.is_empty());
+        assert!(!Cache::resized_source_path(&config, "products/cutout", base_image_path, "default").exists());
+
+        //purging another size does not drop the results
+        cache.write().unwrap().get_mut("products/cutout").unwrap().size_generations.insert(String::from("low"), 1);
+        let image = OptimizeImage::new("products/cutout", "default", vec![Extension::WEBP]);
+        save_images(Arc::new(config.clone()), cache.clone(), image, &HashMap::from([(String::from("default"), 1)])).unwrap();
+
+        assert!(cache.read().unwrap()["products/cutout"].has("default", Extension::WEBP));
+    }
+
+    #[test]
+    fn test_decode_bounds() {
+        let (wide, square) = (String::from("wide"), String::from("square"));
+        let extensions = vec![Extension::JPEG];
+        let default = &Config::default().sizes["default"];
+        let wide_size = Size { width: 1000, height: 100, ..default.clone() };
+        let square_size = Size { width: 300, height: 300, ..default.clone() };
+
+        //the wide box has the larger area, but a square source fits larger in the square one
+        let sizes = vec![(&wide, &wide_size, &extensions), (&square, &square_size, &extensions)];
+        assert_eq!(decode_bounds(2000, 2000, &sizes), Some((300, 300)));
+        assert_eq!(decode_bounds(4000, 400, &sizes), Some((1000, 100)));
+        assert_eq!(decode_bounds(2000, 2000, &[]), None);
+    }
+
+    #[test]
+    fn test_is_smaller() {
+        let config = Config {
+            min_savings: Some(10.0),
+            ..Config::default()
+        };
+
+        assert!(is_smaller(&config, &[0; 80], 100));
+        assert!(!is_smaller(&config, &[0; 95], 100));
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::config::{Config, Extension};
+use crate::error::Error;
+use crate::images;
+
+/// Describes how an optimized image was produced, entries are appended one JSON
+/// object per line so concurrent writers never have to rewrite the file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub image_id: String,
+    pub size: String,
+    pub extension: Extension,
+    pub quality: f32,
+    /// The optimized image was not smaller than the source and got discarded
+    #[serde(default)]
+    pub use_source: bool,
+    /// The format is lossy and the image is encoded losslessly, the variant was not produced
+    #[serde(default)]
+    pub skipped: bool,
+}
+
+pub type Manifest = HashMap<(String, String, Extension), ManifestEntry>;
+
+pub fn path(config: &Config) -> PathBuf {
+    let mut path = PathBuf::from(&config.cache_directory);
+    path.push("manifest.jsonl");
+
+    path
+}
+
+pub fn append(config: &Config, entry: &ManifestEntry) -> Result<(), Error> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    fs::create_dir_all(&config.cache_directory)?;
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path(config))?
+        .write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+/// Appends an entry overriding the earlier ones of a variant, so a discarded variant does not
+/// come back on restart
+pub fn forget(config: &Config, image_id: String, size: String, extension: Extension) -> Result<(), Error> {
+    append(config, &ManifestEntry {
+        image_id,
+        size,
+        extension,
+        quality: 0.0,
+        use_source: false,
+        skipped: false,
+    })
+}
+
+/// Only the variants served from the source or skipped and the qualities found by a target search
+/// can not be found again from the optimized files, other entries are only appended to override them
+pub fn is_needed(config: &Config, entry: &ManifestEntry) -> bool {
+    entry.use_source || entry.skipped || config.sizes.get(&entry.size).is_some_and(|size| size.target.is_some())
+}
+
+/// Later entries override earlier ones as images get optimized again when modified. The file
+/// is rewritten with the entries still needed so it does not grow with every optimization
+pub fn load(config: &Config) -> Manifest {
+    let Ok(content) = fs::read_to_string(path(config)) else {
+        return Manifest::new();
+    };
+
+    let mut manifest = content.lines()
+        .filter_map(|line| serde_json::from_str::<ManifestEntry>(line).ok())
+        .map(|entry| ((entry.image_id.clone(), entry.size.clone(), entry.extension), entry))
+        .collect::<Manifest>();
+
+    manifest.retain(|_, entry| is_needed(config, entry));
+
+    if manifest.len() < content.lines().count() {
+        if let Err(error) = compact(config, &manifest) {
+            warn!("Failed to compact the manifest: {}", error);
+        }
+    }
+
+    manifest
+}
+
+fn compact(config: &Config, manifest: &Manifest) -> Result<(), Error> {
+    let mut content = String::new();
+    for entry in manifest.values() {
+        content.push_str(&serde_json::to_string(entry)?);
+        content.push('\n');
+    }
+
+    images::write(path(config), content.as_bytes(), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Target;
+    use crate::utils;
+
+    fn config() -> Config {
+        let mut config = Config {
+            cache_directory: utils::temp_dir("manifest"),
+            ..Config::default()
+        };
+        let mut low = config.sizes["default"].clone();
+        low.target = Some(Target::Bytes(30000));
+        config.sizes.insert(String::from("low"), low);
+
+        config
+    }
+
+    fn entry(size: &str, quality: f32, use_source: bool) -> ManifestEntry {
+        ManifestEntry {
+            image_id: String::from("products/monitor"),
+            size: size.to_owned(),
+            extension: Extension::AVIF,
+            quality,
+            use_source,
+            skipped: false,
+        }
+    }
+
+    #[test]
+    fn test_append_and_load() {
+        let config = config();
+        let _ = fs::remove_file(path(&config));
+
+        append(&config, &entry("low", 40.0, false)).unwrap();
+        append(&config, &entry("low", 55.0, false)).unwrap();
+
+        let manifest = load(&config);
+
+        assert_eq!(manifest.len(), 1);
+        assert_eq!(manifest[&(String::from("products/monitor"), String::from("low"), Extension::AVIF)], entry("low", 55.0, false));
+    }
+
+    #[test]
+    fn test_load_compacts() {
+        let mut config = config();
+        config.cache_directory.push_str("_compact");
+        let _ = fs::remove_file(path(&config));
+
+        assert!(!is_needed(&config, &entry("default", 90.0, false)));
+        assert!(is_needed(&config, &entry("default", 90.0, true)));
+        assert!(is_needed(&config, &entry("low", 40.0, false)));
+
+        for quality in [40.0, 45.0, 50.0] {
+            append(&config, &entry("low", quality, false)).unwrap();
+        }
+        //served from the source, then purged
+        append(&config, &entry("default", 90.0, true)).unwrap();
+        append(&config, &entry("default", 0.0, false)).unwrap();
+
+        let manifest = load(&config);
+
+        assert_eq!(manifest.len(), 1);
+        assert_eq!(fs::read_to_string(path(&config)).unwrap().lines().count(), 1);
+        assert_eq!(load(&config), manifest);
+    }
+}
@@ -1,68 +1,88 @@
+mod budget;
+mod cancellation;
 mod file_saver;
+mod manifest;
 mod pre_optimizer;
+mod registry;
+mod scheduler;
 mod watcher;
 
+pub use registry::{attach, reap};
+
 use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::fs::File;
-use std::ops::Deref;
+use std::io::ErrorKind;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, mpsc, RwLock};
-use std::sync::mpsc::Sender;
-use std::thread;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::{mem, thread};
 use chrono::{DateTime, Utc};
 use headers_accept::Accept;
 use image::ImageFormat;
 use mediatype::MediaType;
 use walkdir::WalkDir;
 use crate::backend::FileTransfer;
+use crate::cache::cancellation::Cancellation;
 use crate::cache::file_saver::OptimizeImage;
-use crate::config::{Config, Extension};
+use crate::cache::scheduler::{Priority, Scheduler};
+use crate::config::{Config, Extension, OverLimit, SharedConfig};
 use crate::error::Error;
-use crate::utils;
+use crate::{images, utils};
 
 pub type CacheData = Arc<RwLock<HashMap<String, CacheImage>>>;
 
 pub struct Cache {
-    config: Config,
+    config: SharedConfig,
     data: CacheData,
-    create_image_tx: Sender<OptimizeImage>,
+    scheduler: Scheduler,
+    cancellation: Cancellation,
 }
 
 impl Cache {
-    pub fn new(config: &Config) -> Self {
-        let (tx, rx) = mpsc::channel();
+    pub fn new(config: Config) -> Self {
+        let scheduler = Scheduler::new(config.pre_optimizer_threads.unwrap_or(1));
         let data = CacheData::default();
+        let config = config.into_shared();
+        let cancellation = Cancellation::new();
 
         let thread_config = config.clone();
         let thread_data = data.clone();
-        let thread_tx = tx.clone();
+        let thread_scheduler = scheduler.clone();
+        let thread_cancellation = cancellation.clone();
+        let guard = cancellation.register();
 
         //done in a thread to avoid varnish hanging for seconds on startup, but could also
         //lead to 404s if requests are made right after varnish was started
         //could be improved by fetching from disk before returning a 404 ? or too complex for not much ?
         thread::spawn(move || {
-            Self::load_images(&thread_config, thread_data.clone());
+            let _guard = guard;
+            //not holding the lock while scanning, a reload would wait for it
+            let config = thread_config.read().expect("Failed to load images").clone();
+            Self::load_images(&config, thread_data.clone(), &thread_cancellation);
+
+            if thread_cancellation.is_cancelled() {
+                return;
+            }
 
-            file_saver::spawn(thread_config.clone(), thread_data.clone(), rx);
-            watcher::spawn(thread_config.clone(), thread_data.clone(), thread_tx.clone());
-            pre_optimizer::spawn(thread_config.clone(), thread_data.clone(), thread_tx.clone());
+            file_saver::spawn(thread_config.clone(), thread_data.clone(), thread_scheduler.clone(), thread_cancellation.clone());
+            watcher::spawn(thread_config.clone(), thread_data.clone(), thread_scheduler.clone(), thread_cancellation.clone());
+            pre_optimizer::spawn(thread_config.clone(), thread_data.clone(), thread_scheduler.clone(), thread_cancellation.clone());
         });
 
         Cache {
-            config: config.clone(),
+            config,
             data,
-            create_image_tx: tx,
+            scheduler,
+            cancellation,
         }
     }
 
-    fn load_images(config: &Config, images: CacheData) {
+    fn load_images(config: &Config, images: CacheData, cancellation: &Cancellation) {
         let mut lock = images.write().unwrap();
 
-        let supported_extensions = ImageFormat::all()
-            .flat_map(ImageFormat::extensions_str)
-            .map(Deref::deref)
-            .collect::<HashSet<&str>>();
+        let supported_extensions = images::supported_extensions();
+        let manifest = manifest::load(config);
 
         let files = config.roots.iter()
             .flat_map(|root| WalkDir::new(root).into_iter()
@@ -71,11 +91,21 @@ impl Cache {
                 .map(|e| (root.clone(), e)));
 
         for (root, file) in files {
+            if cancellation.is_cancelled() {
+                return;
+            }
+
             let filename = file.path().to_string_lossy().to_string();
             let filename_without_root = file.path().strip_prefix(root).unwrap().to_str().unwrap();
 
             if let (Some(stem), Some(extension)) = utils::decompose_filename(filename_without_root) {
-                if !supported_extensions.contains(extension) {
+                if !supported_extensions.contains(extension.to_lowercase().as_str()) {
+                    continue;
+                }
+
+                //variants are stored by id, images of different roots can not share one
+                if let Some(existing) = lock.get(stem).filter(|existing| existing.shadows(config, &filename)) {
+                    warn!("Image {} has the same id as {} of another root, only the first one is served", filename, existing.base_image_path);
                     continue;
                 }
 
@@ -89,8 +119,22 @@ impl Cache {
                         path.push(stem);
                         path.set_extension(extension.extensions().first().unwrap());
 
+                        let entry = manifest.get(&(stem.to_owned(), size.to_owned(), *extension));
+
                         if path.exists() {
                             item.add(size.to_owned(), extension.to_owned(), path);
+
+                            if let Some(entry) = entry {
+                                item.qualities.insert((size.to_owned(), *extension), entry.quality);
+                            }
+                        }
+
+                        if entry.is_some_and(|entry| entry.use_source) {
+                            item.use_source.insert((size.to_owned(), *extension));
+                        }
+
+                        if entry.is_some_and(|entry| entry.skipped) {
+                            item.skipped.insert((size.to_owned(), *extension));
                         }
                     }
                 }
@@ -100,78 +144,511 @@ impl Cache {
         }
     }
 
-    pub fn get(&self, image_id: &str, size: &str, accept: Option<Accept>) -> Result<Option<FetchResult>, Error> {
+    /// Current configuration, a reloaded configuration only applies to the requests made after the reload
+    pub fn config(&self) -> Result<Arc<Config>, Error> {
+        Ok(self.config.read()?.clone())
+    }
+
+    /// Images are looked up in every root, unless a root is given
+    pub fn get(&self, image_id: &str, size: &str, root: Option<&str>, accept: Option<Accept>) -> Result<Option<FetchResult>, Error> {
+        let config = self.config()?;
         let lock = self.data.read()?;
-        let Some(cache) = lock.get(image_id) else {
+        let Some(cache) = lock.get(image_id).filter(|cache| cache.is_in(root)) else {
             return Ok(None);
         };
 
+        let is_svg = images::svg::is_svg(&cache.base_image_path);
+        if is_svg && !config.sizes.get(size).is_some_and(|s| s.optimizes(&cache.base_image_path)) {
+            return self.read_svg(&config, image_id, &cache.base_image_path, true);
+        }
+
+        if cache.over_limit.get() == Some(&true) {
+            return self.read_over_limit(&config, &cache.base_image_path);
+        }
+
         //convert unavailable extensions
-        for extension in self.config.extensions.iter().filter(|ext| !cache.has(size, **ext)) {
-            let _ = self.create_image_tx.send(OptimizeImage {
-                image_id: image_id.to_owned(),
-                size: size.to_owned(),
-                extension: *extension,
-            });
+        let unavailable_extensions = config.extensions.iter()
+            .filter(|ext| !cache.has(size, **ext))
+            .copied()
+            .collect::<Vec<Extension>>();
+
+        if !unavailable_extensions.is_empty() {
+            let _ = self.scheduler.send(Priority::OnDemand, OptimizeImage::new(image_id, size, unavailable_extensions));
         }
 
-        let converted_extensions = self.config.extensions.iter()
-            .filter(|ext| cache.has(size, **ext))
-            .map(|ext| ext.to_media_type())
-            .collect::<Vec<MediaType>>();
+        let appropriate_extension = self.appropriate_extension(&config, cache, size, accept.as_ref());
+        //cached for long only once it is the format negotiation settles on, a client is otherwise kept
+        //with the format served until the preferred one is converted
+        let is_settled = appropriate_extension == self.settled_extension(&config, cache, size, accept.as_ref());
 
-        let appropriate_extension = accept.as_ref()
-            .and_then(|accept| accept.negotiate(converted_extensions.iter()))
-            .and_then(|media_type| Extension::from_ext(media_type.subty.as_str()))
-            .unwrap_or(self.config.default_format);
+        if cache.uses_source(size, appropriate_extension) && Self::accepts_source(&cache.base_image_path, appropriate_extension, accept.as_ref()) {
+            return self.read_source(&config, image_id, size, appropriate_extension, cache, is_settled);
+        }
 
         if let Some(file) = cache.get(size, appropriate_extension) {
             let path = Path::new(file);
 
             if path.exists() {
-                return self.read_image(file, true);
+                return self.read_image(file, is_settled);
             } else {
                 //the image was in cache but the file did not exist,
                 //maybe it got deleted
-                let _ = self.create_image_tx.send(OptimizeImage {
-                    image_id: image_id.to_owned(),
-                    size: size.to_owned(),
-                    extension: appropriate_extension,
-                });
+                let _ = self.scheduler.send(Priority::OnDemand, OptimizeImage::new(image_id, size, vec![appropriate_extension]));
             }
+        } else if cache.uses_source(size, appropriate_extension) {
+            //flagged before the optimized image was kept along with the source, it is needed
+            //for the clients that do not accept the source format
+            let _ = self.scheduler.send(Priority::OnDemand, OptimizeImage::new(image_id, size, vec![appropriate_extension]));
         }
 
         //return the image as is, it will be optimized later
+        if is_svg {
+            self.read_svg(&config, image_id, &cache.base_image_path, false)
+        } else if self.rejects(&config, cache) {
+            //the file saver will flag the image, but it must not be served in the meantime
+            Ok(None)
+        } else {
+            self.read_image(&cache.base_image_path, false)
+        }
+    }
+
+    /// Swaps the configuration and deletes the optimized images of the sizes whose settings
+    /// changed, the sizes to pre-optimize are optimized again. Settings the background threads
+    /// were started with can not be reloaded
+    pub fn reload(&self, new: Config) -> Result<(), Error> {
+        let config = self.config()?;
+
+        if new.roots != config.roots || new.cache_directory != config.cache_directory {
+            return Error::err("Changing roots or cache_directory requires reloading the VCL");
+        }
+
+        if new.pre_optimizer_threads != config.pre_optimizer_threads || new.max_encode_memory != config.max_encode_memory {
+            warn!("pre_optimizer_threads and max_encode_memory only change when the VCL is reloaded");
+        }
+
+        let outdated_sizes = config.outdated_sizes(&new);
+        let removed_extensions = config.extensions.iter()
+            .filter(|extension| !new.extensions.contains(extension))
+            .copied()
+            .collect::<Vec<Extension>>();
+        let limits_changed = new.limits != config.limits;
+        *self.config.write()? = Arc::new(new);
+
+        if limits_changed {
+            for cache in self.data.write()?.values_mut() {
+                cache.over_limit = OnceLock::new();
+            }
+        }
+
+        for size in outdated_sizes {
+            info!("Settings of size {} changed, deleting its optimized images", size);
+            self.purge_variants(None, Some(&size), true)?;
+        }
+
+        if !removed_extensions.is_empty() {
+            info!("Extensions {:?} are no longer configured, deleting their optimized images", removed_extensions);
+            self.purge_extensions(&removed_extensions)?;
+        }
+
+        Ok(())
+    }
+
+    /// Dimensions of the source of an image, None for unknown images, for the images that are not
+    /// in the root when one is given and for SVGs
+    pub fn dimensions(&self, image_id: &str, root: Option<&str>) -> Result<Option<(u32, u32)>, Error> {
+        Ok(self.data.read()?.get(image_id).filter(|cache| cache.is_in(root)).and_then(|cache| cache.dimensions))
+    }
+
+    /// Format negotiation settles on for an image once it is converted, None if the image does not exist.
+    /// It does not change as variants get converted, the other formats served in the meantime are not cached
+    pub fn negotiate(&self, image_id: &str, size: &str, root: Option<&str>, accept: Option<&Accept>) -> Result<Option<Extension>, Error> {
+        let config = self.config()?;
+        let lock = self.data.read()?;
+
+        Ok(lock.get(image_id).filter(|cache| cache.is_in(root)).map(|cache| self.settled_extension(&config, cache, size, accept)))
+    }
+
+    /// Format a client accepts among all the configured formats, whether images were
+    /// converted or not, it only depends on the Accept header so it makes a stable cache key
+    pub fn accept_key(&self, accept: Option<&Accept>) -> Result<Extension, Error> {
+        let config = self.config()?;
+
+        Ok(self.negotiate_among(&config, config.extensions.iter(), accept))
+    }
+
+    /// Negotiates among the extensions the image will be converted to
+    fn settled_extension(&self, config: &Config, cache: &CacheImage, size: &str, accept: Option<&Accept>) -> Extension {
+        let extensions = config.extensions.iter()
+            .filter(|ext| !cache.skips(size, **ext));
+
+        self.negotiate_among(config, extensions, accept)
+    }
+
+    /// Negotiates among the extensions already converted, the default format is
+    /// picked until the preferred one is available
+    fn appropriate_extension(&self, config: &Config, cache: &CacheImage, size: &str, accept: Option<&Accept>) -> Extension {
+        let converted_extensions = config.extensions.iter()
+            .filter(|ext| cache.has(size, **ext) && !cache.skips(size, **ext));
+
+        self.negotiate_among(config, converted_extensions, accept)
+    }
+
+    fn negotiate_among<'a>(&self, config: &Config, extensions: impl Iterator<Item = &'a Extension>, accept: Option<&Accept>) -> Extension {
+        let media_types = extensions
+            .map(|ext| ext.to_media_type())
+            .collect::<Vec<MediaType>>();
+
+        accept
+            .and_then(|accept| accept.negotiate(media_types.iter()))
+            .and_then(|media_type| Extension::from_ext(media_type.subty.as_str()))
+            .unwrap_or(config.default_format)
+    }
+
+    /// The header is only read on the first request, the verdict is kept until the source
+    /// changes or the limits are reloaded
+    fn rejects(&self, config: &Config, cache: &CacheImage) -> bool {
+        match &config.limits {
+            Some(limits) if limits.over_limit == Some(OverLimit::Reject) => *cache.over_limit.get_or_init(|| {
+                matches!(images::check_limits(&cache.base_image_path, limits), Err(Error::LimitExceeded(_)))
+            }),
+            _ => false,
+        }
+    }
+
+    /// Sources exceeding the limits are never decoded, they are served as is or rejected
+    fn read_over_limit(&self, config: &Config, base_image_path: &str) -> Result<Option<FetchResult>, Error> {
+        let over_limit = config.limits.as_ref().and_then(|limits| limits.over_limit);
+
+        match over_limit.unwrap_or(OverLimit::Serve) {
+            OverLimit::Serve => self.read_image(base_image_path, false),
+            OverLimit::Reject => Ok(None),
+        }
+    }
+
+    /// The optimized image was not smaller than the source format encoded at the same size,
+    /// which is served instead
+    fn read_source(&self, config: &Config, image_id: &str, size: &str, extension: Extension, cache: &CacheImage, is_settled: bool) -> Result<Option<FetchResult>, Error> {
+        let path = Self::resized_source_path(config, image_id, &cache.base_image_path, size);
+
+        if path.exists() {
+            return self.read_image(&path.to_string_lossy(), is_settled);
+        }
+
+        //it got deleted, it is encoded again along with the variant
+        let _ = self.scheduler.send(Priority::OnDemand, OptimizeImage::new(image_id, size, vec![extension]));
+
         self.read_image(&cache.base_image_path, false)
     }
 
+    /// Optimizes the missing variants of an image ahead of requests, for a single size or
+    /// for every size matching the image
+    pub fn prewarm(&self, image_id: &str, size: Option<&str>) -> Result<(), Error> {
+        let config = self.config()?;
+
+        if let Some(size) = size.filter(|size| !config.sizes.contains_key(*size)) {
+            return Error::err(format!("Unknown size {}", size));
+        }
+
+        let variants = {
+            let lock = self.data.read()?;
+            let Some(cache) = lock.get(image_id) else {
+                return Error::err(format!("Unknown image {}", image_id));
+            };
+
+            config.sizes.iter()
+                .filter(|(size_name, _)| size.is_none_or(|size| size == size_name.as_str()))
+                .filter(|(_, size)| size.matches(image_id) && size.optimizes(&cache.base_image_path))
+                .map(|(size_name, _)| {
+                    let extensions = config.extensions.iter()
+                        .filter(|extension| !cache.has(size_name, **extension))
+                        .copied()
+                        .collect::<Vec<Extension>>();
+
+                    (size_name.clone(), extensions)
+                })
+                .filter(|(_, extensions)| !extensions.is_empty())
+                .collect::<HashMap<String, Vec<Extension>>>()
+        };
+
+        if !variants.is_empty() {
+            //requested by a publisher, the image is as good as modified
+            self.scheduler.send(Priority::Watcher, OptimizeImage {
+                image_id: image_id.to_owned(),
+                variants,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes every variant of an image, the source is kept
+    pub fn purge(&self, image_id: &str, reoptimize: bool) -> Result<(), Error> {
+        if !self.data.read()?.contains_key(image_id) {
+            return Error::err(format!("Unknown image {}", image_id));
+        }
+
+        self.purge_variants(Some(image_id), None, reoptimize)
+    }
+
+    /// Deletes the variants of every image for a size
+    pub fn purge_size(&self, size: &str, reoptimize: bool) -> Result<(), Error> {
+        if !self.config()?.sizes.contains_key(size) {
+            return Error::err(format!("Unknown size {}", size));
+        }
+
+        self.purge_variants(None, Some(size), reoptimize)
+    }
+
+    pub fn purge_all(&self, reoptimize: bool) -> Result<(), Error> {
+        self.purge_variants(None, None, reoptimize)
+    }
+
+    fn purge_variants(&self, image_id: Option<&str>, size: Option<&str>, reoptimize: bool) -> Result<(), Error> {
+        let config = self.config()?;
+        let purges_size = |size_name: &str| size.is_none_or(|size| size == size_name);
+        //the size may have been removed by a reload
+        let size_names = size.map_or_else(|| config.sizes.keys().map(String::as_str).collect(), |size| vec![size]);
+        let mut to_delete = Vec::new();
+        let mut to_forget = Vec::new();
+        let mut to_optimize = Vec::new();
+
+        {
+            let mut lock = self.data.write()?;
+            let images = lock.iter_mut().filter(|(id, _)| image_id.is_none_or(|image_id| image_id == id.as_str()));
+
+            //queued jobs would bring the purged variants back, the running ones are dropped by the generation
+            self.scheduler.discard(image_id, size);
+
+            for (id, cache) in images {
+                match size {
+                    Some(size) => *cache.size_generations.entry(size.to_owned()).or_default() += 1,
+                    None => cache.generation += 1,
+                }
+
+                let (purged, kept) = mem::take(&mut cache.optimized).into_iter()
+                    .partition::<HashMap<_, _>, _>(|((size_name, _), _)| purges_size(size_name));
+
+                cache.optimized = kept;
+                cache.qualities.retain(|(size_name, _), _| !purges_size(size_name));
+                to_delete.extend(purged.into_values().map(PathBuf::from));
+
+                for (size_name, extension) in cache.use_source.iter().chain(&cache.skipped).filter(|(size_name, _)| purges_size(size_name)) {
+                    to_forget.push((id.clone(), size_name.clone(), *extension));
+                }
+                cache.use_source.retain(|(size_name, _)| !purges_size(size_name));
+                cache.skipped.retain(|(size_name, _)| !purges_size(size_name));
+
+                for size_name in &size_names {
+                    to_delete.push(Self::resized_source_path(&config, id, &cache.base_image_path, size_name));
+                }
+
+                if size.is_none() {
+                    cache.over_limit = OnceLock::new();
+                    to_delete.push(Self::sanitized_svg_path(&config, id));
+                }
+
+                if reoptimize {
+                    let variants = Self::pre_optimize_variants(&config, id, &cache.base_image_path).into_iter()
+                        .filter(|(size_name, _)| purges_size(size_name))
+                        .collect::<HashMap<String, Vec<Extension>>>();
+
+                    if !variants.is_empty() {
+                        to_optimize.push(OptimizeImage {
+                            image_id: id.clone(),
+                            variants,
+                        });
+                    }
+                }
+            }
+        }
+
+        for path in to_delete {
+            match fs::remove_file(path) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+                _ => {},
+            }
+        }
+
+        for (image_id, size, extension) in to_forget {
+            manifest::forget(&config, image_id, size, extension)?;
+        }
+
+        for image in to_optimize {
+            self.scheduler.send(Priority::PreOptimize, image)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the variants of every image for extensions, the running jobs may still encode them
+    /// with the previous configuration so their results are dropped
+    fn purge_extensions(&self, extensions: &[Extension]) -> Result<(), Error> {
+        let config = self.config()?;
+        let purges = |extension: &Extension| extensions.contains(extension);
+        let mut to_delete = Vec::new();
+        let mut to_forget = Vec::new();
+
+        {
+            let mut lock = self.data.write()?;
+
+            for (id, cache) in lock.iter_mut() {
+                cache.generation += 1;
+
+                let (purged, kept) = mem::take(&mut cache.optimized).into_iter()
+                    .partition::<HashMap<_, _>, _>(|((_, extension), _)| purges(extension));
+
+                cache.optimized = kept;
+                cache.qualities.retain(|(_, extension), _| !purges(extension));
+                to_delete.extend(purged.into_values());
+
+                for (size_name, extension) in cache.use_source.iter().chain(&cache.skipped).filter(|(_, extension)| purges(extension)) {
+                    to_forget.push((id.clone(), size_name.clone(), *extension));
+                }
+                cache.use_source.retain(|(_, extension)| !purges(extension));
+                cache.skipped.retain(|(_, extension)| !purges(extension));
+            }
+        }
+
+        for path in to_delete {
+            match fs::remove_file(path) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+                _ => {},
+            }
+        }
+
+        for (image_id, size, extension) in to_forget {
+            manifest::forget(&config, image_id, size, extension)?;
+        }
+
+        Ok(())
+    }
+
+    /// Variants of an image to optimize ahead of requests
+    pub fn pre_optimize_variants(config: &Config, image_id: &str, base_image_path: &str) -> HashMap<String, Vec<Extension>> {
+        config.sizes.iter()
+            .filter(|(_, size)| size.matches(image_id) && size.optimizes(base_image_path) && size.pre_optimize.unwrap_or(false))
+            .map(|(size_name, _)| (size_name.clone(), config.extensions.clone()))
+            .collect()
+    }
+
+    pub fn sanitized_svg_path(config: &Config, image_id: &str) -> PathBuf {
+        let mut path = PathBuf::from(&config.cache_directory);
+        path.push(image_id);
+        path.set_extension(images::svg::EXTENSIONS[0]);
+
+        path
+    }
+
+    /// Format the source is resized to when optimized images are not smaller than it, sources
+    /// that are not JPEG, WEBP, AVIF or PNG are served as JPEG as browsers may not display them
+    pub fn source_format(base_image_path: &str) -> ImageFormat {
+        match ImageFormat::from_path(base_image_path) {
+            Ok(format @ (ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Avif | ImageFormat::Png)) => format,
+            _ => ImageFormat::Jpeg,
+        }
+    }
+
+    /// The resized source replaces the negotiated variant only if the client accepts its format,
+    /// without an Accept header only a variant in the same format is replaced
+    fn accepts_source(base_image_path: &str, extension: Extension, accept: Option<&Accept>) -> bool {
+        let format = Self::source_format(base_image_path);
+
+        if extension.image_format() == format {
+            return true;
+        }
+
+        let Ok(media_type) = MediaType::parse(format.to_mime_type()) else {
+            return false;
+        };
+
+        accept.is_some_and(|accept| accept.negotiate([&media_type]).is_some())
+    }
+
+    pub fn resized_source_path(config: &Config, image_id: &str, base_image_path: &str, size: &str) -> PathBuf {
+        let extension = Self::source_format(base_image_path).extensions_str()[0];
+
+        let mut path = PathBuf::from(&config.cache_directory);
+        path.push(size);
+        path.push(format!("{}.source.{}", image_id, extension));
+
+        path
+    }
+
+    fn read_svg(&self, config: &Config, image_id: &str, base_image_path: &str, is_optimized: bool) -> Result<Option<FetchResult>, Error> {
+        let path = Self::sanitized_svg_path(config, image_id);
+
+        //svgs are never served without being sanitized first, concurrent first requests
+        //all sanitize the source and the file is replaced atomically
+        if !path.exists() {
+            images::write(&path, images::svg::sanitize(base_image_path)?.as_bytes(), None)?;
+        }
+
+        self.read_image(&path.to_string_lossy(), is_optimized)
+    }
+
     fn read_image(&self, path: &str, is_optimized: bool) -> Result<Option<FetchResult>, Error> {
         let file = File::open(path)?;
         let metadata = file.metadata()?;
-        let format = ImageFormat::from_path(path)?;
+        let mime = images::mime_type(path)?;
 
         Ok(Some(FetchResult {
             data: FileTransfer::new(file, metadata.len()),
             last_modified: DateTime::from(metadata.modified() ? ),
             inode: metadata.ino(),
-            mime: format.to_mime_type(),
+            mime,
             is_optimized,
         }))
     }
 }
 
+impl Drop for Cache {
+    fn drop(&mut self) {
+        info!("Shutting down cache {}", self.config().map_or_else(|_| String::new(), |config| config.cache_directory.clone()));
+
+        //queued jobs are abandoned, they will be queued again by requests or by the next pre-optimization
+        self.cancellation.cancel();
+        self.scheduler.close();
+        registry::stopping(self.cancellation.clone());
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CacheImage {
     pub base_image_path: String,
     pub optimized: HashMap<(String, Extension), String>, //associating size and extension to the path
+    pub qualities: HashMap<(String, Extension), f32>, //quality the optimized images were encoded with
+    pub use_source: HashSet<(String, Extension)>, //optimized images that were not smaller than the source
+    pub skipped: HashSet<(String, Extension)>, //lossy formats not produced as the image is encoded losslessly
+    pub over_limit: OnceLock<bool>, //whether the source exceeds the configured limits and can not be optimized, once checked
+    pub generation: u64, //bumped when every variant is purged, the jobs started before can not save theirs
+    pub size_generations: HashMap<String, u64>, //bumped when the variants of a single size are purged
+    pub dimensions: Option<(u32, u32)>, //of the source, read when it is found or modified
 }
 
 impl CacheImage {
     pub fn new(base_image_path: String) -> Self {
+        let dimensions = Self::source_dimensions(&base_image_path);
+
         CacheImage {
             base_image_path,
             optimized: HashMap::new(),
+            qualities: HashMap::new(),
+            use_source: HashSet::new(),
+            skipped: HashSet::new(),
+            over_limit: OnceLock::new(),
+            generation: 0,
+            size_generations: HashMap::new(),
+            dimensions,
+        }
+    }
+
+    /// Only the header is read, SVGs have none
+    pub fn source_dimensions(base_image_path: &str) -> Option<(u32, u32)> {
+        if images::svg::is_svg(base_image_path) {
+            return None;
         }
+
+        images::dimensions(base_image_path).ok()
     }
 
     pub fn add<P: AsRef<Path>>(&mut self, size: String, ext: Extension, path: P) {
@@ -183,7 +660,31 @@ impl CacheImage {
     }
 
     pub fn has(&self, size: &str, ext: Extension) -> bool {
-        self.optimized.contains_key(&(size.to_string(), ext))
+        self.optimized.contains_key(&(size.to_string(), ext)) || self.uses_source(size, ext) || self.skips(size, ext)
+    }
+
+    pub fn uses_source(&self, size: &str, ext: Extension) -> bool {
+        self.use_source.contains(&(size.to_string(), ext))
+    }
+
+    pub fn skips(&self, size: &str, ext: Extension) -> bool {
+        self.skipped.contains(&(size.to_string(), ext))
+    }
+
+    /// Changes whenever the variants of the size are purged
+    pub fn generation(&self, size: &str) -> u64 {
+        self.generation + self.size_generations.get(size).copied().unwrap_or(0)
+    }
+
+    pub fn is_in(&self, root: Option<&str>) -> bool {
+        root.is_none_or(|root| Path::new(&self.base_image_path).starts_with(root))
+    }
+
+    /// Whether the image hides the one at `image_path`, which has the same id in another root
+    pub fn shadows(&self, config: &Config, image_path: &str) -> bool {
+        config.roots.iter()
+            .find(|root| Path::new(image_path).starts_with(root))
+            .is_some_and(|root| !self.is_in(Some(root)))
     }
 }
 
@@ -195,3 +696,303 @@ pub struct FetchResult {
     pub mime: &'static str,
     pub is_optimized: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::config::Limits;
+
+    fn variant(config: &Config, size: &str) -> PathBuf {
+        let path = PathBuf::from(&config.cache_directory).join(size).join("products/monitor.avif");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"avif").unwrap();
+
+        path
+    }
+
+    fn cache<'a>(config: &Config, images: impl IntoIterator<Item = (&'a str, CacheImage)>) -> Cache {
+        Cache {
+            config: config.clone().into_shared(),
+            data: CacheData::new(RwLock::new(images.into_iter().map(|(id, image)| (id.to_owned(), image)).collect())),
+            scheduler: Scheduler::new(1),
+            cancellation: Cancellation::new(),
+        }
+    }
+
+    #[test]
+    fn test_load_images_indexes_heic() {
+        let root = PathBuf::from(utils::temp_dir("heic_root"));
+        fs::create_dir_all(root.join("products")).unwrap();
+        fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/badge.heic"), root.join("products/badge.heic")).unwrap();
+
+        let config = Config {
+            roots: vec![root.to_string_lossy().to_string()],
+            cache_directory: utils::temp_dir("heic"),
+            ..Config::default()
+        };
+
+        let data = CacheData::default();
+        Cache::load_images(&config, data.clone(), &Cancellation::new());
+
+        let image = &data.read().unwrap()["products/badge"];
+        assert!(image.base_image_path.ends_with("products/badge.heic"));
+    }
+
+    #[test]
+    fn test_load_images_keeps_first_root() {
+        let roots = ["first_root", "second_root"].map(|root| PathBuf::from(utils::temp_dir(root)));
+        for root in &roots {
+            fs::create_dir_all(root.join("products")).unwrap();
+            fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/cutout.png"), root.join("products/cutout.png")).unwrap();
+        }
+
+        let config = Config {
+            roots: roots.iter().map(|root| root.to_string_lossy().to_string()).collect(),
+            cache_directory: utils::temp_dir("load_roots"),
+            ..Config::default()
+        };
+
+        let data = CacheData::default();
+        Cache::load_images(&config, data.clone(), &Cancellation::new());
+
+        let image = &data.read().unwrap()["products/cutout"];
+        assert!(image.is_in(Some(&config.roots[0])));
+        assert!(image.shadows(&config, &roots[1].join("products/cutout.png").to_string_lossy()));
+        assert!(!image.shadows(&config, &roots[0].join("products/cutout.png").to_string_lossy()));
+    }
+
+    #[test]
+    fn test_purge() {
+        let mut config = Config {
+            cache_directory: utils::temp_dir("purge"),
+            ..Config::default()
+        };
+        let mut low = config.sizes["default"].clone();
+        low.pre_optimize = Some(true);
+        config.sizes.insert(String::from("low"), low);
+
+        let mut image = CacheImage::new(String::from("/var/www/media/products/monitor.jpg"));
+        let low = variant(&config, "low");
+        let default = variant(&config, "default");
+        image.add(String::from("low"), Extension::AVIF, &low);
+        image.add(String::from("default"), Extension::AVIF, &default);
+        image.use_source.insert((String::from("default"), Extension::WEBP));
+
+        let cache = cache(&config, [("products/monitor", image)]);
+
+        cache.purge_size("low", true).unwrap();
+        assert!(!low.exists() && default.exists());
+        assert!(!cache.data.read().unwrap()["products/monitor"].has("low", Extension::AVIF));
+        assert_eq!(cache.scheduler.pending(Priority::PreOptimize), 1);
+        //the running jobs of the other sizes keep their results
+        assert_eq!(cache.data.read().unwrap()["products/monitor"].generation("default"), 0);
+        assert_eq!(cache.data.read().unwrap()["products/monitor"].generation("low"), 1);
+
+        //the queued job of the image is dropped along with its variants
+        cache.purge("products/monitor", false).unwrap();
+        let image = &cache.data.read().unwrap()["products/monitor"];
+        assert!(!default.exists());
+        assert!(image.optimized.is_empty() && image.use_source.is_empty());
+        assert_eq!(image.generation("low"), 2);
+        assert_eq!(cache.scheduler.pending(Priority::PreOptimize), 0);
+
+        assert!(cache.purge_size("unknown", false).is_err());
+        assert!(cache.purge("products/unknown", false).is_err());
+    }
+
+    #[test]
+    fn test_read_source() {
+        let config = Config {
+            cache_directory: utils::temp_dir("read_source"),
+            ..Config::default()
+        };
+        let base_image_path = concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/cutout.png");
+
+        let mut image = CacheImage::new(base_image_path.to_owned());
+        image.use_source.insert((String::from("default"), Extension::AVIF));
+
+        let cache = cache(&config, [("products/cutout", image)]);
+
+        let resized_source = Cache::resized_source_path(&config, "products/cutout", base_image_path, "default");
+        assert!(resized_source.ends_with("default/products/cutout.source.png"));
+        let _ = fs::remove_file(&resized_source);
+        let accept = || Some(Accept::from_str("image/avif,image/*").unwrap());
+
+        //encoded again when missing, the source is served in the meantime
+        let result = cache.get("products/cutout", "default", None, accept()).unwrap().unwrap();
+        assert!(!result.is_optimized);
+        assert_eq!(cache.scheduler.pending(Priority::OnDemand), 1);
+
+        images::write(&resized_source, b"png", None).unwrap();
+        let result = cache.get("products/cutout", "default", None, accept()).unwrap().unwrap();
+        assert!(result.is_optimized);
+        assert_eq!(result.data.size(), 3);
+        assert_eq!(result.mime, "image/png");
+
+        //read once when the image was found
+        assert_eq!(cache.dimensions("products/cutout", None).unwrap(), images::dimensions(base_image_path).ok());
+        assert_eq!(cache.dimensions("products/cutout", Some("/var/www/other")).unwrap(), None);
+
+        assert_eq!(Cache::source_format("photo.heic"), ImageFormat::Jpeg);
+        assert_eq!(Cache::source_format("photo.webp"), ImageFormat::WebP);
+    }
+
+    #[test]
+    fn test_read_source_respects_accept() {
+        let config = Config {
+            cache_directory: utils::temp_dir("read_source_accept"),
+            ..Config::default()
+        };
+        let base_image_path = concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/monitor.webp");
+
+        let mut image = CacheImage::new(base_image_path.to_owned());
+        let jpeg = PathBuf::from(&config.cache_directory).join("default/products/monitor.jpeg");
+        image.add(String::from("default"), Extension::JPEG, &jpeg);
+        image.use_source.insert((String::from("default"), Extension::JPEG));
+
+        let cache = cache(&config, [("products/monitor", image)]);
+
+        let resized_source = Cache::resized_source_path(&config, "products/monitor", base_image_path, "default");
+        images::write(&resized_source, b"webp", None).unwrap();
+        images::write(&jpeg, b"jpeg", None).unwrap();
+
+        //the optimized image is served to the clients that do not accept the source format
+        let result = cache.get("products/monitor", "default", None, Some(Accept::from_str("image/jpeg").unwrap())).unwrap().unwrap();
+        assert_eq!(result.mime, "image/jpeg");
+
+        let result = cache.get("products/monitor", "default", None, Some(Accept::from_str("image/webp,image/jpeg").unwrap())).unwrap().unwrap();
+        assert_eq!(result.mime, "image/webp");
+    }
+
+    #[test]
+    fn test_reload() {
+        let config = Config {
+            cache_directory: utils::temp_dir("reload"),
+            ..Config::default()
+        };
+
+        let mut image = CacheImage::new(String::from("/var/www/media/products/monitor.jpg"));
+        let default = variant(&config, "default");
+        image.add(String::from("default"), Extension::AVIF, &default);
+
+        let cache = cache(&config, [("products/monitor", image)]);
+
+        cache.reload(Config { default_format: Extension::WEBP, ..config.clone() }).unwrap();
+        assert_eq!(cache.config().unwrap().default_format, Extension::WEBP);
+        assert!(default.exists());
+        assert_eq!(cache.data.read().unwrap()["products/monitor"].generation("default"), 0);
+
+        let mut changed = config.clone();
+        changed.sizes.get_mut("default").unwrap().width = 800;
+        cache.reload(changed.clone()).unwrap();
+        assert!(!default.exists());
+        assert!(cache.data.read().unwrap()["products/monitor"].optimized.is_empty());
+        //the jobs running with the previous settings can not save their results
+        assert_eq!(cache.data.read().unwrap()["products/monitor"].generation("default"), 1);
+
+        //the variants of an extension that is no longer configured are deleted
+        let default = variant(&config, "default");
+        cache.data.write().unwrap().get_mut("products/monitor").unwrap().add(String::from("default"), Extension::AVIF, &default);
+        cache.reload(Config { extensions: vec![Extension::WEBP], ..changed }).unwrap();
+        assert!(!default.exists());
+        assert!(cache.data.read().unwrap()["products/monitor"].optimized.is_empty());
+        assert_eq!(cache.data.read().unwrap()["products/monitor"].generation("default"), 2);
+
+        //the resized sources of a removed size are deleted
+        let mut with_low = Config::clone(&cache.config().unwrap());
+        with_low.sizes.insert(String::from("low"), with_low.sizes["default"].clone());
+        cache.reload(with_low.clone()).unwrap();
+        let resized_source = Cache::resized_source_path(&with_low, "products/monitor", "/var/www/media/products/monitor.jpg", "low");
+        images::write(&resized_source, b"jpeg", None).unwrap();
+        with_low.sizes.remove("low");
+        cache.reload(with_low).unwrap();
+        assert!(!resized_source.exists());
+
+        assert!(cache.reload(Config { roots: vec![String::from("/var/www")], ..config }).is_err());
+    }
+
+    #[test]
+    fn test_rejects_over_limit() {
+        let config = Config {
+            cache_directory: utils::temp_dir("rejects"),
+            limits: Some(Limits { max_width: Some(32), over_limit: Some(OverLimit::Reject), ..Limits::default() }),
+            ..Config::default()
+        };
+        let base_image_path = concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/cutout.png");
+
+        let cache = cache(&config, [("products/cutout", CacheImage::new(base_image_path.to_owned()))]);
+
+        assert!(cache.get("products/cutout", "default", None, None).unwrap().is_none());
+        assert_eq!(cache.data.read().unwrap()["products/cutout"].over_limit.get(), Some(&true));
+
+        //the verdict is kept, no optimization is requested for a rejected source
+        while cache.scheduler.pending(Priority::OnDemand) > 0 {
+            cache.scheduler.next().unwrap();
+        }
+        assert!(cache.get("products/cutout", "default", None, None).unwrap().is_none());
+        assert_eq!(cache.scheduler.pending(Priority::OnDemand), 0);
+
+        cache.reload(Config { limits: None, ..config }).unwrap();
+        assert!(cache.data.read().unwrap()["products/cutout"].over_limit.get().is_none());
+        assert!(cache.get("products/cutout", "default", None, None).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let config = Config {
+            extensions: vec![Extension::AVIF, Extension::WEBP],
+            cache_directory: utils::temp_dir("negotiate"),
+            ..Config::default()
+        };
+
+        let mut image = CacheImage::new(String::from("/var/www/media/products/monitor.jpg"));
+        image.add(String::from("default"), Extension::WEBP, variant(&config, "default"));
+
+        let cache = cache(&config, [("products/monitor", image)]);
+
+        let accept = Accept::from_str("image/avif,image/webp,*/*;q=0.8").unwrap();
+        //only the WEBP variant exists, the AVIF one will be served once converted
+        assert_eq!(cache.negotiate("products/monitor", "default", None, Some(&accept)).unwrap(), Some(Extension::AVIF));
+        assert_eq!(cache.negotiate("products/monitor", "default", None, None).unwrap(), Some(Extension::JPEG));
+        assert_eq!(cache.negotiate("products/monitor", "default", Some("/var/www/media"), None).unwrap(), Some(Extension::JPEG));
+        assert_eq!(cache.negotiate("products/monitor", "default", Some("/var/www/other"), None).unwrap(), None);
+        assert_eq!(cache.negotiate("products/missing", "default", None, Some(&accept)).unwrap(), None);
+
+        //served meanwhile without being cached
+        assert!(!cache.get("products/monitor", "default", None, Some(Accept::from_str("image/avif,image/webp,*/*;q=0.8").unwrap())).unwrap().unwrap().is_optimized);
+
+        //the lossless WEBP is served instead of the AVIF that is not produced
+        cache.data.write().unwrap().get_mut("products/monitor").unwrap().skipped.insert((String::from("default"), Extension::AVIF));
+        assert_eq!(cache.negotiate("products/monitor", "default", None, Some(&accept)).unwrap(), Some(Extension::WEBP));
+        assert!(cache.get("products/monitor", "default", None, Some(Accept::from_str("image/avif,image/webp,*/*;q=0.8").unwrap())).unwrap().unwrap().is_optimized);
+
+        assert_eq!(cache.accept_key(Some(&accept)).unwrap(), Extension::AVIF);
+        assert_eq!(cache.accept_key(Some(&Accept::from_str("image/webp,image/png").unwrap())).unwrap(), Extension::WEBP);
+        assert_eq!(cache.accept_key(Some(&Accept::from_str("image/png").unwrap())).unwrap(), Extension::JPEG);
+        assert_eq!(cache.accept_key(None).unwrap(), Extension::JPEG);
+    }
+
+    #[test]
+    fn test_prewarm() {
+        let config = Config {
+            extensions: vec![Extension::AVIF, Extension::WEBP],
+            cache_directory: utils::temp_dir("prewarm"),
+            ..Config::default()
+        };
+
+        let mut image = CacheImage::new(String::from("/var/www/media/products/monitor.jpg"));
+        image.add(String::from("default"), Extension::AVIF, variant(&config, "default"));
+
+        let cache = cache(&config, [("products/monitor", image)]);
+
+        assert!(cache.prewarm("products/missing", None).is_err());
+        assert!(cache.prewarm("products/monitor", Some("unknown")).is_err());
+
+        cache.prewarm("products/monitor", Some("default")).unwrap();
+        let (job, _running) = cache.scheduler.next().unwrap();
+
+        assert_eq!(job.image_id, "products/monitor");
+        assert_eq!(job.variants, HashMap::from([(String::from("default"), vec![Extension::WEBP])]));
+    }
+}
@@ -1,32 +1,49 @@
-use std::sync::mpsc::Sender;
+use std::collections::HashMap;
 use std::thread;
-use itertools::Itertools;
 use crate::cache::CacheData;
+use crate::cache::cancellation::Cancellation;
 use crate::cache::file_saver::OptimizeImage;
-use crate::config::{Config, Extension, Size};
+use crate::cache::scheduler::{Priority, Scheduler};
+use crate::config::{Extension, SharedConfig};
 
-pub fn spawn(config: Config, data: CacheData, create_image_tx: Sender<OptimizeImage>) {
+pub fn spawn(config: SharedConfig, data: CacheData, scheduler: Scheduler, cancellation: Cancellation) {
+    let config = config.read().expect("Failed to start pre-optimizer thread").clone();
     let data = (*data.read().expect("Failed to start pre-optimizer thread")).clone();
+    let guard = cancellation.register();
 
     thread::spawn(move || {
+        let _guard = guard;
         let sizes_to_optimize = config.sizes.iter()
             .filter(|(_, size)| size.pre_optimize.unwrap_or(false))
-            .cartesian_product(config.extensions.iter())
-            .map(|((size_name, size), extension)| (size_name, size, extension))
-            .collect::<Vec<(&String, &Size, &Extension)>>();
+            .collect::<Vec<_>>();
 
-        for (size_name, size, &extension) in sizes_to_optimize {
-            for (image_id, cache) in &data {
-                if !size.matches(image_id) {
-                    continue;
-                }
+        for (image_id, cache) in &data {
+            if cancellation.is_cancelled() {
+                break;
+            }
+
+            let variants = sizes_to_optimize.iter()
+                .filter(|(_, size)| size.matches(image_id) && size.optimizes(&cache.base_image_path))
+                .map(|(size_name, _)| {
+                    let extensions = config.extensions.iter()
+                        .filter(|extension| !cache.has(size_name, **extension))
+                        .copied()
+                        .collect::<Vec<Extension>>();
+
+                    (size_name.to_string(), extensions)
+                })
+                .filter(|(_, extensions)| !extensions.is_empty())
+                .collect::<HashMap<String, Vec<Extension>>>();
+
+            if !variants.is_empty() {
+                let image = OptimizeImage {
+                    image_id: image_id.to_owned(),
+                    variants,
+                };
 
-                if !cache.optimized.contains_key(&(size_name.to_owned(), extension)) {
-                    create_image_tx.send(OptimizeImage {
-                        image_id: image_id.to_owned(),
-                        size: size_name.to_owned(),
-                        extension,
-                    }).unwrap();
+                if let Err(error) = scheduler.send(Priority::PreOptimize, image) {
+                    warn!("Pre-optimization stopped: {}", error);
+                    break;
                 }
             }
         }
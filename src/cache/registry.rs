@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::Duration;
+use crate::cache::cancellation::Cancellation;
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::error::Error;
+
+/// Caches in use keyed by their cache directory, objects are dropped when their VCL is
+/// discarded and the cache is shut down with the last one
+static CACHES: LazyLock<Mutex<HashMap<String, Weak<Cache>>>> = LazyLock::new(Default::default);
+
+/// Caches that were shut down and whose threads may still be running
+static STOPPING: Mutex<Vec<Cancellation>> = Mutex::new(Vec::new());
+
+/// Returns the cache already using the cache directory of the configuration, so that VCL
+/// reloads and objects sharing a configuration do not scan the roots and spawn threads again.
+/// Two caches can not use the same directory, a different configuration is reloaded into the
+/// cache in use and only fails when its roots differ
+pub fn attach(config: Config) -> Result<Arc<Cache>, Error> {
+    let mut caches = CACHES.lock()?;
+    caches.retain(|_, cache| cache.strong_count() > 0);
+
+    let key = config.cache_directory.clone();
+    if let Some(cache) = caches.get(&key).and_then(Weak::upgrade) {
+        let current = cache.config()?;
+
+        if current.roots != config.roots {
+            return Error::err(format!(
+                "Cache directory {} is used with other roots, discard the VCL using it first", key,
+            ));
+        }
+
+        if *current != config {
+            info!("Cache directory {} is used with another configuration, reloading it", key);
+            cache.reload(config)?;
+        }
+
+        return Ok(cache);
+    }
+
+    let cache = Arc::new(Cache::new(config));
+    caches.insert(key, Arc::downgrade(&cache));
+
+    Ok(cache)
+}
+
+pub fn stopping(cancellation: Cancellation) {
+    if let Ok(mut stopping) = STOPPING.lock() {
+        stopping.push(cancellation);
+    }
+}
+
+/// Waits for the threads of the caches that were shut down to exit, running jobs are
+/// completed while queued ones were abandoned
+pub fn reap(timeout: Duration) -> Result<(), Error> {
+    let stopping = std::mem::take(&mut *STOPPING.lock()?);
+
+    for cancellation in stopping {
+        if !cancellation.wait(timeout) {
+            warn!("Background threads did not stop within {:?}, they will exit after their current job", timeout);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::config::Extension;
+    use crate::utils;
+
+    #[test]
+    fn test_attach() {
+        let root = PathBuf::from(utils::temp_dir("attach_root"));
+        fs::create_dir_all(&root).unwrap();
+
+        let config = Config {
+            roots: vec![root.to_string_lossy().to_string()],
+            cache_directory: utils::temp_dir("attach"),
+            ..Config::default()
+        };
+
+        let first = attach(config.clone()).unwrap();
+        let second = attach(config.clone()).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        //an edited configuration is reloaded into the cache in use
+        let edited = attach(Config { default_format: Extension::WEBP, ..config.clone() }).unwrap();
+        assert!(Arc::ptr_eq(&first, &edited));
+        assert_eq!(first.config().unwrap().default_format, Extension::WEBP);
+
+        assert!(attach(Config { roots: vec![String::from("/var/www/other")], ..config.clone() }).is_err());
+
+        //the directory is free again once every object using it is dropped
+        drop((first, second, edited));
+        let third = attach(Config { default_format: Extension::AVIF, ..config }).unwrap();
+        assert_eq!(third.config().unwrap().default_format, Extension::AVIF);
+    }
+}
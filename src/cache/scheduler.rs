@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use crate::cache::file_saver::OptimizeImage;
+use crate::error::Error;
+
+/// Origin of a job, in decreasing order of priority
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Priority {
+    /// A client requested a variant that does not exist yet
+    OnDemand,
+    /// A source was modified
+    Watcher,
+    /// Backfill of the sizes to pre-optimize
+    PreOptimize,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::OnDemand, Priority::Watcher, Priority::PreOptimize];
+
+    /// Share of the threads each class can use at once, on-demand jobs can use all of them
+    /// while the background classes always leave threads available for live requests
+    fn share(&self, threads: usize) -> usize {
+        match self {
+            Priority::OnDemand => threads,
+            Priority::Watcher => (threads / 2).max(1),
+            Priority::PreOptimize => (threads / 4).max(1),
+        }
+    }
+}
+
+struct State {
+    queues: [VecDeque<OptimizeImage>; 3],
+    running: [usize; 3],
+    closed: bool,
+}
+
+/// Replaces a plain channel so live requests never wait behind a backfill, the job
+/// of the highest priority class that did not exhaust its share is always picked first
+#[derive(Clone)]
+pub struct Scheduler {
+    threads: usize,
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+/// Keeps the job counted as running until dropped
+pub struct Running {
+    priority: Priority,
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl Scheduler {
+    pub fn new(threads: usize) -> Self {
+        Scheduler {
+            threads,
+            state: Arc::new((Mutex::new(State {
+                queues: Default::default(),
+                running: [0; 3],
+                closed: false,
+            }), Condvar::new())),
+        }
+    }
+
+    pub fn send(&self, priority: Priority, image: OptimizeImage) -> Result<(), Error> {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock()?;
+
+        if state.closed {
+            return Error::err("Scheduler is closed");
+        }
+
+        state.queues[priority as usize].push_back(image);
+        condvar.notify_all();
+
+        Ok(())
+    }
+
+    /// Blocks until a job can be started, fails once the scheduler is closed
+    pub fn next(&self) -> Result<(OptimizeImage, Running), Error> {
+        let (lock, condvar) = &*self.state;
+        let mut state = lock.lock()?;
+
+        loop {
+            if state.closed {
+                return Error::err("Scheduler is closed");
+            }
+
+            if state.running.iter().sum::<usize>() < self.threads {
+                let priority = Priority::ALL.into_iter().find(|priority| {
+                    let index = *priority as usize;
+                    !state.queues[index].is_empty() && state.running[index] < priority.share(self.threads)
+                });
+
+                if let Some(priority) = priority {
+                    let index = priority as usize;
+                    let image = state.queues[index].pop_front().expect("Logic error: queue should not be empty");
+                    state.running[index] += 1;
+
+                    return Ok((image, Running {
+                        priority,
+                        state: self.state.clone(),
+                    }));
+                }
+            }
+
+            state = condvar.wait(state)?;
+        }
+    }
+
+    /// Abandons the queued jobs and wakes up the threads waiting for a job so they can exit,
+    /// running jobs are left to complete
+    pub fn close(&self) {
+        let (lock, condvar) = &*self.state;
+        if let Ok(mut state) = lock.lock() {
+            state.closed = true;
+            state.queues.iter_mut().for_each(VecDeque::clear);
+        }
+
+        condvar.notify_all();
+    }
+
+    /// Drops the queued variants of an image or of a size, or every queued job when neither is given
+    pub fn discard(&self, image_id: Option<&str>, size: Option<&str>) {
+        let Ok(mut state) = self.state.0.lock() else {
+            return;
+        };
+
+        for queue in state.queues.iter_mut() {
+            queue.retain_mut(|image| {
+                if image_id.is_none_or(|image_id| image_id == image.image_id) {
+                    image.variants.retain(|size_name, _| size.is_some_and(|size| size != size_name));
+                }
+
+                !image.variants.is_empty()
+            });
+        }
+    }
+
+    pub fn pending(&self, priority: Priority) -> usize {
+        self.state.0.lock().map_or(0, |state| state.queues[priority as usize].len())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.state;
+        if let Ok(mut state) = lock.lock() {
+            state.running[self.priority as usize] -= 1;
+        }
+
+        condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::config::Extension;
+
+    fn job(image_id: &str) -> OptimizeImage {
+        OptimizeImage::new(image_id, "low", vec![Extension::AVIF])
+    }
+
+    #[test]
+    fn test_on_demand_first() {
+        let scheduler = Scheduler::new(4);
+        scheduler.send(Priority::PreOptimize, job("backfill")).unwrap();
+        scheduler.send(Priority::Watcher, job("modified")).unwrap();
+        scheduler.send(Priority::OnDemand, job("requested")).unwrap();
+
+        let (first, _first) = scheduler.next().unwrap();
+        let (second, _second) = scheduler.next().unwrap();
+        let (third, _third) = scheduler.next().unwrap();
+
+        assert_eq!(first.image_id, "requested");
+        assert_eq!(second.image_id, "modified");
+        assert_eq!(third.image_id, "backfill");
+    }
+
+    #[test]
+    fn test_background_share() {
+        let scheduler = Scheduler::new(4);
+        scheduler.send(Priority::PreOptimize, job("first")).unwrap();
+        scheduler.send(Priority::PreOptimize, job("second")).unwrap();
+
+        //pre-optimization only gets one of the four threads
+        let (first, backfill) = scheduler.next().unwrap();
+        assert_eq!(first.image_id, "first");
+
+        scheduler.send(Priority::OnDemand, job("requested")).unwrap();
+        let (requested, _requested) = scheduler.next().unwrap();
+        assert_eq!(requested.image_id, "requested");
+        assert_eq!(scheduler.pending(Priority::PreOptimize), 1);
+
+        drop(backfill);
+        let (second, _second) = scheduler.next().unwrap();
+        assert_eq!(second.image_id, "second");
+    }
+
+    #[test]
+    fn test_discard() {
+        let scheduler = Scheduler::new(1);
+        scheduler.send(Priority::OnDemand, job("purged")).unwrap();
+        scheduler.send(Priority::Watcher, OptimizeImage {
+            image_id: String::from("kept"),
+            variants: HashMap::from([(String::from("low"), vec![Extension::AVIF]), (String::from("high"), vec![Extension::AVIF])]),
+        }).unwrap();
+
+        scheduler.discard(Some("purged"), None);
+        assert_eq!(scheduler.pending(Priority::OnDemand), 0);
+
+        scheduler.discard(None, Some("low"));
+        let (kept, _kept) = scheduler.next().unwrap();
+        assert_eq!(kept.variants.keys().collect::<Vec<&String>>(), vec!["high"]);
+    }
+
+    #[test]
+    fn test_close() {
+        let scheduler = Scheduler::new(1);
+        scheduler.send(Priority::OnDemand, job("requested")).unwrap();
+
+        let waiting = scheduler.clone();
+        let (running, _running) = scheduler.next().unwrap();
+        let thread = std::thread::spawn(move || waiting.next().is_err());
+
+        scheduler.close();
+
+        assert_eq!(running.image_id, "requested");
+        assert!(thread.join().unwrap());
+        assert!(scheduler.send(Priority::OnDemand, job("late")).is_err());
+    }
+}
@@ -1,37 +1,59 @@
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::OnceLock;
+use std::time::Duration;
 use std::{fs, mem, sync, thread};
-use std::collections::HashMap;
-use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
 use notify::{Config as NotifyConfig, Error as NotifyError, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use notify::event::{AccessKind, AccessMode, ModifyKind, RemoveKind, RenameMode};
-use crate::cache::{CacheData, CacheImage};
+use crate::cache::{Cache, CacheData, CacheImage};
+use crate::cache::cancellation::Cancellation;
 use crate::cache::file_saver::OptimizeImage;
-use crate::config::Config;
+use crate::cache::manifest;
+use crate::cache::scheduler::{Priority, Scheduler};
+use crate::config::{Config, SharedConfig};
 use crate::error::Error;
 
-pub fn spawn(config: Config, data: CacheData, create_image_tx: Sender<OptimizeImage>) {
+/// How often the watcher checks whether it was cancelled when there is no event
+const CANCELLATION_INTERVAL: Duration = Duration::from_millis(250);
+
+pub fn spawn(config: SharedConfig, data: CacheData, scheduler: Scheduler, cancellation: Cancellation) {
+    let guard = cancellation.register();
+
     thread::spawn(move || {
+        let _guard = guard;
         let (tx, rx) = sync::mpsc::channel();
+        let roots = config.read().expect("Failed to start watcher thread").roots.clone();
 
         let mut watcher = RecommendedWatcher::new(tx, NotifyConfig::default()).unwrap();
-        for root in &config.roots {
+        for root in &roots {
             watcher.watch(Path::new(root), RecursiveMode::Recursive).unwrap();
         }
 
-        event_handler(config, data, rx, create_image_tx);
+        //the watcher stops watching when dropped at the end of the thread
+        event_handler(config, data, rx, scheduler, cancellation);
     });
 }
 
-fn event_handler(config: Config, data: CacheData, rx: Receiver<Result<Event, NotifyError>>, create_image_tx: Sender<OptimizeImage>) {
-    while let Ok(result) = rx.recv() {
+fn event_handler(config: SharedConfig, data: CacheData, rx: Receiver<Result<Event, NotifyError>>, scheduler: Scheduler, cancellation: Cancellation) {
+    while !cancellation.is_cancelled() {
+        let result = match rx.recv_timeout(CANCELLATION_INTERVAL) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
         match result {
             Ok(event) => {
+                let Ok(config) = config.read().map(|config| config.clone()) else {
+                    break;
+                };
+
                 let result = match event.kind {
-                    EventKind::Access(AccessKind::Close(AccessMode::Write)) => handle_modification(event, &config, &data, create_image_tx.clone()),
+                    EventKind::Access(AccessKind::Close(AccessMode::Write)) => handle_modification(event, &config, &data, scheduler.clone()),
                     EventKind::Remove(RemoveKind::File) => handle_deletion(event, &config, &data),
                     EventKind::Modify(ModifyKind::Name(RenameMode::From)) => handle_deletion(event, &config, &data),
-                    EventKind::Modify(ModifyKind::Name(RenameMode::To)) => handle_modification(event, &config, &data, create_image_tx.clone()),
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To)) => handle_modification(event, &config, &data, scheduler.clone()),
                     _ => Ok(()),
                 };
 
@@ -44,21 +66,31 @@ fn event_handler(config: Config, data: CacheData, rx: Receiver<Result<Event, Not
     }
 }
 
-fn handle_modification(event: Event, config: &Config, data: &CacheData, create_image_tx: Sender<OptimizeImage>) -> Result<(), Error> {
+fn handle_modification(event: Event, config: &Config, data: &CacheData, scheduler: Scheduler) -> Result<(), Error> {
     let image_path = get_image_path(&event)?;
     let image_id = get_image_id(&image_path, &config);
 
-    let to_delete = {
+    let (to_delete, to_forget) = {
         let mut lock = data.write()?;
 
+        if let Some(cache) = lock.get(&image_id).filter(|cache| cache.shadows(config, &image_path)) {
+            warn!("Image {} has the same id as {} of another root, it is not served", image_path, cache.base_image_path);
+            return Ok(());
+        }
+
         if !lock.contains_key(&image_id) {
             lock.insert(image_id.to_string(), CacheImage::new(image_path.to_owned()));
         }
 
         if let Some(cache) = lock.get_mut(&image_id) {
-            mem::take(&mut cache.optimized)
+            cache.qualities.clear();
+            cache.over_limit = OnceLock::new();
+            cache.dimensions = CacheImage::source_dimensions(&image_path);
+            cache.generation += 1;
+            let to_forget = mem::take(&mut cache.use_source).into_iter().chain(mem::take(&mut cache.skipped)).collect();
+            (mem::take(&mut cache.optimized), to_forget)
         } else {
-            HashMap::new()
+            (HashMap::new(), HashSet::new())
         }
     };
 
@@ -66,15 +98,20 @@ fn handle_modification(event: Event, config: &Config, data: &CacheData, create_i
         fs::remove_file(path)?;
     }
 
-    let to_optimize = config.sizes.iter()
-        .filter(|(_, size)| size.matches(&image_id) && size.pre_optimize.unwrap_or(false))
-        .cartesian_product(config.extensions.iter());
+    //the modified source may give smaller optimized images, the manifest must not flag them on restart
+    for (size, extension) in to_forget {
+        manifest::forget(config, image_id.clone(), size, extension)?;
+    }
+
+    remove_resized_sources(config, &image_id, &image_path)?;
+    remove_sanitized_svg(config, &image_id)?;
 
-    for ((size_name, _), &format) in to_optimize {
-        create_image_tx.send(OptimizeImage {
-            image_id: image_id.clone(),
-            size: size_name.clone(),
-            extension: format,
+    let variants = Cache::pre_optimize_variants(config, &image_id, &image_path);
+
+    if !variants.is_empty() {
+        scheduler.send(Priority::Watcher, OptimizeImage {
+            image_id,
+            variants,
         })?;
     }
 
@@ -85,7 +122,16 @@ fn handle_deletion(event: Event, config: &Config, data: &CacheData) -> Result<()
     let image_path = get_image_path(&event)?;
     let image_id = get_image_id(&image_path, &config);
 
-    let image = data.write()?.remove(&image_id);
+    let image = {
+        let mut lock = data.write()?;
+
+        //the image served for the id is in another root
+        if lock.get(&image_id).is_some_and(|cache| cache.shadows(config, &image_path)) {
+            return Ok(());
+        }
+
+        lock.remove(&image_id)
+    };
 
     if let Some(image) = image {
         for (_, path) in image.optimized {
@@ -93,6 +139,27 @@ fn handle_deletion(event: Event, config: &Config, data: &CacheData) -> Result<()
         }
     }
 
+    remove_resized_sources(config, &image_id, &image_path)?;
+    remove_sanitized_svg(config, &image_id)
+}
+
+fn remove_resized_sources(config: &Config, image_id: &str, image_path: &str) -> Result<(), Error> {
+    for size in config.sizes.keys() {
+        let path = Cache::resized_source_path(config, image_id, image_path, size);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_sanitized_svg(config: &Config, image_id: &str) -> Result<(), Error> {
+    let path = Cache::sanitized_svg_path(config, image_id);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
     Ok(())
 }
 
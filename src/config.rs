@@ -1,32 +1,98 @@
 use std::collections::HashMap;
+use std::fmt::Display;
 use std::fs;
+use std::sync::{Arc, RwLock};
 use image::ImageFormat;
+use itertools::Itertools;
 use log::LevelFilter;
 use mediatype::MediaType;
 use mediatype::names::{AVIF, IMAGE, JPEG, WEBP};
 use regex::Regex;
 use ron::extensions::Extensions;
 use ron::Options;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use crate::error::Error;
+use crate::images;
 use crate::images::OptimizationConfig;
 
+/// Configuration shared with the background threads, swapped as a whole when reloaded
+pub type SharedConfig = Arc<RwLock<Arc<Config>>>;
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
     pub extensions: Vec<Extension>,
     pub default_format: Extension,
     pub roots: Vec<String>,
-    pub url: String,
+    #[serde(default)]
+    pub routes: Vec<Route>,
     pub cache_directory: String,
     pub pre_optimizer_threads: Option<usize>,
+    pub max_encode_memory: Option<u64>,
+    pub min_savings: Option<f32>,
+    pub limits: Option<Limits>,
+    pub vary_header: Option<String>,
+    pub unknown_query_params: Option<UnknownParams>,
     pub sizes: HashMap<String, Size>,
     pub logger: Option<Logger>,
 
-    #[serde(skip_deserializing)]
-    pub url_regex: Option<Regex>,
+    #[serde(rename = "url")]
+    pub url_serialized: Option<String>,
 
     #[serde(rename = "qualities")]
     pub quality_serialized: Option<HashMap<Extension, f32>>,
+
+    #[serde(rename = "encoder")]
+    pub encoder_serialized: Option<Encoder>,
+}
+
+/// URL pattern images are served from, routes are tried in order
+#[derive(Deserialize, Clone, Debug)]
+pub struct Route {
+    pub url: String,
+    /// Size of every image served by the route, the URL pattern then has no `{size}` argument
+    pub size: Option<String>,
+    /// Sizes the route serves, all the sizes when not set
+    pub sizes: Option<Vec<String>>,
+    /// Root the images are read from, every root when not set
+    pub root: Option<String>,
+
+    #[serde(skip_deserializing)]
+    pub url_regex: Option<Regex>,
+    #[serde(skip_deserializing)]
+    pub query_params: Vec<QueryParam>,
+}
+
+/// Query parameter of a URL pattern, matched by name in any order
+#[derive(Clone, Debug)]
+pub struct QueryParam {
+    pub name: String,
+    pub value: QueryValue,
+    pub optional: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum QueryValue {
+    /// `{size}`, `{path}` or `{ext}`
+    Argument(String),
+    /// The parameter must have exactly this value
+    Literal(String),
+}
+
+/// What to do with URLs having query parameters the URL pattern does not name
+#[derive(Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum UnknownParams {
+    /// Match the URL anyway, e.g. for cache busting parameters
+    Ignore,
+    /// The URL does not match the route
+    Reject,
+}
+
+/// Image a URL points to
+#[derive(PartialEq, Debug)]
+pub struct ImageUrl {
+    pub size: String,
+    pub path: String,
+    pub root: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -37,21 +103,78 @@ pub struct Size {
     pub quality: [f32; 3],
     pub pattern: Option<String>,
     pub pre_optimize: Option<bool>,
+    pub rasterize_svg: Option<bool>,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    pub target: Option<Target>,
+    pub compression: Option<Compression>,
+    pub linear_resize: Option<bool>,
 
     #[serde(skip_deserializing)]
     pub pattern_regex: Option<Regex>,
 
     #[serde(rename = "qualities")]
     pub quality_serialized: Option<HashMap<Extension, f32>>,
+
+    #[serde(skip_deserializing)]
+    pub encoder: Encoder,
+
+    #[serde(rename = "encoder")]
+    pub encoder_serialized: Option<Encoder>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, PartialEq, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Encoder {
+    pub avif: AvifEncoder,
+    pub webp: WebpEncoder,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug, Default)]
+pub struct AvifEncoder {
+    pub speed: Option<u8>,
+    pub alpha_quality: Option<u8>,
+    pub max_threads: Option<usize>,
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug, Default)]
+pub struct WebpEncoder {
+    pub method: Option<u8>,
+    pub sharp_yuv: Option<bool>,
+    pub near_lossless: Option<u8>,
+    pub alpha_quality: Option<u8>,
+    pub filter_strength: Option<u8>,
+    pub filter_sharpness: Option<u8>,
+    pub sns_strength: Option<u8>,
+    pub segments: Option<u8>,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug, Default)]
+pub struct Limits {
+    pub max_pixels: Option<u64>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_file_bytes: Option<u64>,
+    pub max_memory: Option<u64>,
+    pub over_limit: Option<OverLimit>,
+}
+
+/// What to do with sources that exceed the limits
+#[derive(Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum OverLimit {
+    /// Serve the source without resizing nor optimizing it
+    Serve,
+    /// Respond with a 404
+    Reject,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Debug)]
 pub struct Logger {
     pub path: String,
     pub level: Option<LevelFilter>,
 }
 
-#[derive(Deserialize, Eq, PartialEq, Hash, Copy, Clone, Debug)]
+#[derive(Deserialize, Serialize, Eq, PartialEq, Hash, Copy, Clone, Debug)]
 #[repr(u8)]
 pub enum Extension {
     JPEG,
@@ -59,6 +182,30 @@ pub enum Extension {
     AVIF,
 }
 
+#[derive(Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum ChromaSubsampling {
+    YUV444,
+    YUV422,
+    YUV420,
+}
+
+#[derive(Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub enum Compression {
+    Lossy,
+    Lossless,
+    /// Lossless for graphics, lossy for photos
+    Auto,
+}
+
+/// Searches the encoder quality instead of using a fixed one
+#[derive(Deserialize, PartialEq, Copy, Clone, Debug)]
+pub enum Target {
+    /// Maximum DSSIM from the resized image, 0 meaning identical
+    Dssim(f64),
+    /// Maximum size of the encoded image in bytes
+    Bytes(usize),
+}
+
 impl Extension {
     pub fn values() -> [Extension; 3] {
         return [
@@ -76,6 +223,15 @@ impl Extension {
         }
     }
 
+    /// Lowercase name of the format, e.g. `jpeg`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Extension::JPEG => "jpeg",
+            Extension::WEBP => "webp",
+            Extension::AVIF => "avif",
+        }
+    }
+
     pub fn from_ext(value: &str) -> Option<Extension> {
         match value.to_lowercase().as_str() {
             "jpeg" | "jpg" => Some(Extension::JPEG),
@@ -117,14 +273,46 @@ impl Config {
         }
     }
 
+    pub fn into_shared(self) -> SharedConfig {
+        Arc::new(RwLock::new(Arc::new(self)))
+    }
+
+    /// Sizes whose optimized images are outdated with the new configuration, every
+    /// size is outdated when a setting applying to all of them changed
+    pub fn outdated_sizes(&self, new: &Config) -> Vec<String> {
+        let everything = self.min_savings != new.min_savings || self.limits != new.limits;
+
+        self.sizes.iter()
+            .filter(|(size_name, size)| everything || new.sizes.get(*size_name) != Some(size))
+            .map(|(size_name, _)| size_name.clone())
+            .collect()
+    }
+
     fn parse(config: String) -> Result<Config, Error> {
         let mut config = Options::default()
             .with_default_extension(Extensions::IMPLICIT_SOME)
             .from_str::<Config>(&config)?;
 
-        config.url_regex = Some(Self::build_url_regex(&config.url)?);
+        if let Some(url) = config.url_serialized.take() {
+            config.routes.insert(0, Route::new(&url)?);
+        }
 
-        for size in &mut config.sizes.values_mut() {
+        if config.routes.is_empty() {
+            return Error::err("At least one URL pattern is required in url or routes");
+        }
+
+        for route in &mut config.routes {
+            route.compile()?;
+            route.validate(&config.roots, &config.sizes)?;
+        }
+
+        if config.min_savings.is_some_and(|savings| !(0.0..100.0).contains(&savings)) {
+            return Error::err("min_savings must be a percentage between 0 and 100");
+        }
+
+        let config_encoder = config.encoder_serialized.take().unwrap_or_default();
+
+        for (size_name, size) in &mut config.sizes {
             for extension in Extension::values() {
                 let size_quality = size.quality_serialized.as_ref().and_then(|q| q.get(&extension));
                 let config_quality = config.quality_serialized.as_ref().and_then(|q| q.get(&extension));
@@ -140,6 +328,15 @@ impl Config {
 
             size.quality_serialized = None;
 
+            size.encoder = size.encoder_serialized.take().unwrap_or_default().or(&config_encoder);
+            size.encoder.validate().map_err(|e| Error::new(format!("Invalid encoder configuration for size {}: {}", size_name, e)))?;
+
+            match size.target {
+                Some(Target::Dssim(dssim)) if dssim <= 0.0 => return Error::err(format!("DSSIM target of size {} must be positive", size_name)),
+                Some(Target::Bytes(0)) => return Error::err(format!("Bytes target of size {} must be positive", size_name)),
+                _ => {}
+            }
+
             if let Some(pattern) = &size.pattern {
                 size.pattern_regex = Some(Regex::new(pattern)?)
             }
@@ -151,11 +348,14 @@ impl Config {
         Ok(config)
     }
 
+    /// Builds the regex matching the path of URLs, the query parameters of the pattern are
+    /// matched separately so they can be in any order
     fn build_url_regex(url: &str) -> Result<Regex, Error> {
-        if !url.contains("{size}") || !url.contains("{path}") {
-            return Error::err("Arguments {size} and {path} are required in URL pattern");
+        if !url.contains("{path}") {
+            return Error::err("Argument {path} is required in URL pattern");
         }
 
+        let (url, _) = url.split_once('?').unwrap_or((url, ""));
         let clean_url = format!(r"^{}$", regex::escape(url))
             .replace(r"\{size\}", r"(?<size>\w+)")
             .replace(r"\{path\}", r"(?<path>.+?)")
@@ -169,6 +369,268 @@ impl Config {
 
         Ok(Regex::new(&clean_url)?)
     }
+
+    /// Extracts the image from a URL with the first route matching it, None when no route
+    /// matches or when the size does not exist or does not apply to the path
+    pub fn parse_url(&self, url: &str) -> Result<Option<ImageUrl>, Error> {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let path = urlencoding::decode(path)?;
+
+        let query = query.split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                Ok((urlencoding::decode(name)?.into_owned(), urlencoding::decode(value)?.into_owned()))
+            })
+            .collect::<Result<Vec<(String, String)>, Error>>()?;
+
+        let unknown_params = self.unknown_query_params.unwrap_or(UnknownParams::Ignore);
+
+        Ok(self.routes.iter().find_map(|route| route.parse(&path, &query, unknown_params, &self.sizes)))
+    }
+
+    /// Builds the URL of an image from the pattern of the first route serving the size, optional
+    /// parts are only kept when they contain an argument. The extension is only cosmetic as the
+    /// format is negotiated, the default format is used when the pattern requires one and none is given
+    pub fn build_url(&self, path: &str, size: &str, ext: Option<&str>) -> Result<String, Error> {
+        if !self.sizes.contains_key(size) {
+            return Error::err(format!("Unknown size {}", size));
+        }
+
+        let Some(route) = self.routes.iter().find(|route| route.serves(size)) else {
+            return Error::err(format!("No route serves size {}", size));
+        };
+
+        let path = path.split('/').map(urlencoding::encode).join("/");
+
+        let render = |ext: Option<&str>| route.render(&|argument| match argument {
+            "size" => Some(size.to_owned()),
+            "path" => Some(path.clone()),
+            "ext" => ext.map(str::to_owned),
+            _ => None,
+        });
+
+        render(ext)
+            .or_else(|| render(self.default_format.extensions().first().copied()))
+            .ok_or_else(|| Error::new("Invalid URL pattern in config file"))
+    }
+
+    /// Builds a srcset attribute from a comma separated list of sizes, the sizes whose pattern does not
+    /// match the path are skipped. Each URL is described by the width of the source resized to its size,
+    /// or by the maximum width of the size when the dimensions of the source are not known. `source`
+    /// gives the dimensions of the source found in the root of the route serving the size
+    pub fn build_srcset(&self, path: &str, sizes: &str, source: impl Fn(Option<&str>) -> Result<Option<(u32, u32)>, Error>) -> Result<String, Error> {
+        let candidates = sizes.split(',')
+            .map(str::trim)
+            .filter(|size| !size.is_empty())
+            .map(|size_name| match self.sizes.get(size_name) {
+                Some(size) if !size.matches(path) => Ok(None),
+                Some(size) => {
+                    let root = self.routes.iter().find(|route| route.serves(size_name)).and_then(|route| route.root.as_deref());
+                    let width = source(root)?.map_or(size.width, |(width, height)| images::fit_dimensions(width, height, size.width, size.height).0);
+                    Ok(Some(format!("{} {}w", self.build_url(path, size_name, None)?, width)))
+                },
+                None => Error::err(format!("Unknown size {}", size_name)),
+            })
+            .collect::<Result<Vec<Option<String>>, Error>>()?;
+
+        Ok(candidates.into_iter().flatten().join(", "))
+    }
+}
+
+impl Route {
+    pub fn new(url: &str) -> Result<Route, Error> {
+        let mut route = Route {
+            url: url.to_owned(),
+            size: None,
+            sizes: None,
+            root: None,
+            url_regex: None,
+            query_params: Vec::new(),
+        };
+        route.compile()?;
+
+        Ok(route)
+    }
+
+    /// Parses the query parameters after the `?` of the URL pattern, e.g. `size={size}&[fmt={ext}]`,
+    /// parameters between brackets are optional
+    fn compile(&mut self) -> Result<(), Error> {
+        self.url_regex = Some(Config::build_url_regex(&self.url)?);
+
+        let Some((_, query)) = self.url.split_once('?') else {
+            return Ok(());
+        };
+
+        self.query_params = query.split('&')
+            .map(|param| {
+                let (optional, param) = match param.strip_prefix('[').and_then(|param| param.strip_suffix(']')) {
+                    Some(param) => (true, param),
+                    None => (false, param),
+                };
+
+                let Some((name, value)) = param.split_once('=').filter(|(name, _)| !name.is_empty()) else {
+                    return Error::err(format!("Invalid query parameter {} in URL pattern {}", param, self.url));
+                };
+
+                let value = match value.strip_prefix('{').and_then(|value| value.strip_suffix('}')) {
+                    Some(argument @ ("size" | "path" | "ext")) => QueryValue::Argument(argument.to_owned()),
+                    None if !value.contains(['{', '}', '[', ']']) => QueryValue::Literal(value.to_owned()),
+                    _ => return Error::err(format!("Invalid query parameter {} in URL pattern {}", param, self.url)),
+                };
+
+                Ok(QueryParam {
+                    name: name.to_owned(),
+                    value,
+                    optional,
+                })
+            })
+            .collect::<Result<Vec<QueryParam>, Error>>()?;
+
+        Ok(())
+    }
+
+    fn validate(&self, roots: &[String], sizes: &HashMap<String, Size>) -> Result<(), Error> {
+        match (&self.size, self.url.contains("{size}")) {
+            (Some(_), true) => return Error::err(format!("Route {} can not have both a fixed size and a {{size}} argument", self.url)),
+            (None, false) => return Error::err(format!("Argument {{size}} is required in URL pattern {} without a fixed size", self.url)),
+            _ => {}
+        }
+
+        if self.size.is_some() && self.sizes.is_some() {
+            return Error::err(format!("Route {} can not have both a fixed size and sizes", self.url));
+        }
+
+        if let Some(size) = self.size.iter().chain(self.sizes.iter().flatten()).find(|size| !sizes.contains_key(*size)) {
+            return Error::err(format!("Unknown size {} in route {}", size, self.url));
+        }
+
+        match &self.root {
+            Some(root) if !roots.contains(root) => Error::err(format!("Root {} of route {} is not one of the roots", root, self.url)),
+            _ => Ok(()),
+        }
+    }
+
+    fn parse(&self, path: &str, query: &[(String, String)], unknown_params: UnknownParams, sizes: &HashMap<String, Size>) -> Option<ImageUrl> {
+        let regex = self.url_regex.as_ref().expect("Badly initialized config");
+        let captures = regex.captures(path)?;
+
+        let mut arguments = regex.capture_names()
+            .flatten()
+            .filter_map(|name| captures.name(name).map(|value| (name, value.as_str())))
+            .collect::<HashMap<&str, &str>>();
+
+        for param in &self.query_params {
+            match (query.iter().find(|(name, _)| *name == param.name), &param.value) {
+                (Some((_, value)), QueryValue::Argument(argument)) if is_valid_argument(argument, value) => {
+                    arguments.insert(argument.as_str(), value.as_str());
+                },
+                (Some((_, value)), QueryValue::Literal(literal)) if value == literal => {},
+                (None, _) if param.optional => {},
+                _ => return None,
+            }
+        }
+
+        if unknown_params == UnknownParams::Reject && query.iter().any(|(name, _)| self.query_params.iter().all(|param| param.name != *name)) {
+            return None;
+        }
+
+        let size = self.size.clone().or_else(|| arguments.get("size").map(|size| size.to_string()))?;
+        let path = *arguments.get("path")?;
+
+        let applies = self.serves(&size) && sizes.get(&size).is_some_and(|s| s.matches(path));
+
+        applies.then(|| ImageUrl {
+            size,
+            path: path.to_owned(),
+            root: self.root.clone(),
+        })
+    }
+
+    /// Renders the URL pattern, optional query parameters are left out when their argument is missing
+    fn render(&self, argument: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+        let (path, _) = self.url.split_once('?').unwrap_or((self.url.as_str(), ""));
+        let (mut url, _) = render_url(&path.chars().collect::<Vec<char>>(), argument)?;
+
+        let mut separator = '?';
+        for param in &self.query_params {
+            let value = match &param.value {
+                QueryValue::Argument(name) => argument(name),
+                QueryValue::Literal(literal) => Some(urlencoding::encode(literal).into_owned()),
+            };
+
+            match value {
+                Some(value) => {
+                    url.push(separator);
+                    url.push_str(&urlencoding::encode(&param.name));
+                    url.push('=');
+                    url.push_str(&value);
+                    separator = '&';
+                },
+                None if param.optional => {},
+                None => return None,
+            }
+        }
+
+        Some(url)
+    }
+
+    fn serves(&self, size: &str) -> bool {
+        match &self.size {
+            Some(fixed_size) => fixed_size == size,
+            None => self.sizes.as_ref().is_none_or(|sizes| sizes.iter().any(|s| s == size)),
+        }
+    }
+}
+
+/// Query parameters can hold anything, the arguments are checked like in the path
+fn is_valid_argument(argument: &str, value: &str) -> bool {
+    !value.is_empty() && match argument {
+        "size" => value.chars().all(|c| c.is_alphanumeric() || c == '_'),
+        "ext" => value.chars().all(|c| c.is_ascii_alphanumeric()),
+        _ => true,
+    }
+}
+
+/// Renders a URL pattern and tells whether it contained any argument, returns None
+/// when an argument is missing
+fn render_url(pattern: &[char], argument: &dyn Fn(&str) -> Option<String>) -> Option<(String, bool)> {
+    let mut url = String::new();
+    let mut has_arguments = false;
+    let mut index = 0;
+
+    while index < pattern.len() {
+        match pattern[index] {
+            '[' => {
+                let mut depth = 0;
+                let end = index + pattern[index..].iter().position(|c| {
+                    depth += match c { '[' => 1, ']' => -1, _ => 0 };
+                    depth == 0
+                })?;
+
+                if let Some((part, true)) = render_url(&pattern[index + 1..end], argument) {
+                    url.push_str(&part);
+                    has_arguments = true;
+                }
+
+                index = end + 1;
+            },
+            '{' => {
+                let end = index + pattern[index..].iter().position(|c| *c == '}')?;
+                let name = pattern[index + 1..end].iter().collect::<String>();
+
+                url.push_str(&argument(&name)?);
+                has_arguments = true;
+                index = end + 1;
+            },
+            c => {
+                url.push(c);
+                index += 1;
+            },
+        }
+    }
+
+    Some((url, has_arguments))
 }
 
 impl Default for Config {
@@ -179,9 +641,14 @@ impl Default for Config {
             roots: vec![
                 String::from("/dev/null"),
             ],
-            url: String::from("/media"),
+            routes: Vec::new(),
             cache_directory: String::from("/tmp/impress"),
             pre_optimizer_threads: None,
+            max_encode_memory: None,
+            min_savings: None,
+            limits: None,
+            vary_header: None,
+            unknown_query_params: None,
             sizes: HashMap::from([
                 (String::from("default"), Size {
                     width: 500,
@@ -189,13 +656,21 @@ impl Default for Config {
                     quality: [0.0; 3],
                     pattern: None,
                     pre_optimize: None,
+                    rasterize_svg: None,
+                    chroma_subsampling: None,
+                    target: None,
+                    compression: None,
+                    linear_resize: None,
                     pattern_regex: None,
                     quality_serialized: None,
+                    encoder: Encoder::default(),
+                    encoder_serialized: None,
                 }),
             ]),
             logger: None,
-            url_regex: None,
+            url_serialized: None,
             quality_serialized: None,
+            encoder_serialized: None,
         }
     }
 }
@@ -208,23 +683,133 @@ impl Size {
             true
         }
     }
+
+    /// The image is only classified with the `Auto` compression
+    pub fn is_lossless(&self, is_graphic: impl FnOnce() -> bool) -> bool {
+        match self.compression.unwrap_or(Compression::Lossy) {
+            Compression::Lossy => false,
+            Compression::Lossless => true,
+            Compression::Auto => is_graphic(),
+        }
+    }
+
+    /// SVGs are served sanitized and only get converted to the raster formats
+    /// for sizes that explicitly ask for it
+    pub fn optimizes(&self, image_path: &str) -> bool {
+        !images::svg::is_svg(image_path) || self.rasterize_svg.unwrap_or(false)
+    }
+}
+
+/// Objects sharing a cache must agree on every setting, `url`, `qualities` and `encoder`
+/// are folded into the routes and sizes while parsing
+impl PartialEq for Config {
+    fn eq(&self, other: &Config) -> bool {
+        self.extensions == other.extensions
+            && self.default_format == other.default_format
+            && self.roots == other.roots
+            && self.routes == other.routes
+            && self.cache_directory == other.cache_directory
+            && self.pre_optimizer_threads == other.pre_optimizer_threads
+            && self.max_encode_memory == other.max_encode_memory
+            && self.min_savings == other.min_savings
+            && self.limits == other.limits
+            && self.vary_header == other.vary_header
+            && self.unknown_query_params == other.unknown_query_params
+            && self.sizes == other.sizes
+            && self.logger == other.logger
+    }
+}
+
+/// The compiled pattern and query parameters follow from the URL
+impl PartialEq for Route {
+    fn eq(&self, other: &Route) -> bool {
+        self.url == other.url && self.size == other.size && self.sizes == other.sizes && self.root == other.root
+    }
+}
+
+/// Compares the resolved settings, the serialized ones are only set while parsing
+impl PartialEq for Size {
+    fn eq(&self, other: &Size) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && self.quality == other.quality
+            && self.pattern == other.pattern
+            && self.pre_optimize == other.pre_optimize
+            && self.rasterize_svg == other.rasterize_svg
+            && self.chroma_subsampling == other.chroma_subsampling
+            && self.target == other.target
+            && self.compression == other.compression
+            && self.linear_resize == other.linear_resize
+            && self.encoder == other.encoder
+    }
+}
+
+impl Encoder {
+    fn or(self, other: &Encoder) -> Encoder {
+        Encoder {
+            avif: AvifEncoder {
+                speed: self.avif.speed.or(other.avif.speed),
+                alpha_quality: self.avif.alpha_quality.or(other.avif.alpha_quality),
+                max_threads: self.avif.max_threads.or(other.avif.max_threads),
+                chroma_subsampling: self.avif.chroma_subsampling.or(other.avif.chroma_subsampling),
+            },
+            webp: WebpEncoder {
+                method: self.webp.method.or(other.webp.method),
+                sharp_yuv: self.webp.sharp_yuv.or(other.webp.sharp_yuv),
+                near_lossless: self.webp.near_lossless.or(other.webp.near_lossless),
+                alpha_quality: self.webp.alpha_quality.or(other.webp.alpha_quality),
+                filter_strength: self.webp.filter_strength.or(other.webp.filter_strength),
+                filter_sharpness: self.webp.filter_sharpness.or(other.webp.filter_sharpness),
+                sns_strength: self.webp.sns_strength.or(other.webp.sns_strength),
+                segments: self.webp.segments.or(other.webp.segments),
+            },
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        check_range("AVIF speed", self.avif.speed, 0, 10)?;
+        check_range("AVIF alpha_quality", self.avif.alpha_quality, 0, 100)?;
+        check_range("AVIF max_threads", self.avif.max_threads, 1, 64)?;
+        check_range("WEBP method", self.webp.method, 0, 6)?;
+        check_range("WEBP near_lossless", self.webp.near_lossless, 0, 100)?;
+        check_range("WEBP alpha_quality", self.webp.alpha_quality, 0, 100)?;
+        check_range("WEBP filter_strength", self.webp.filter_strength, 0, 100)?;
+        check_range("WEBP filter_sharpness", self.webp.filter_sharpness, 0, 7)?;
+        check_range("WEBP sns_strength", self.webp.sns_strength, 0, 100)?;
+        check_range("WEBP segments", self.webp.segments, 1, 4)
+    }
+}
+
+fn check_range<T>(name: &str, value: Option<T>, min: T, max: T) -> Result<(), Error> where T: PartialOrd + Display {
+    match value {
+        Some(value) if value < min || value > max => Error::err(format!("{} must be between {} and {}, got {}", name, min, max, value)),
+        _ => Ok(()),
+    }
 }
 
 impl OptimizationConfig {
-    pub fn new(size: &Size, format: Extension, prefer_quality: bool) -> OptimizationConfig {
+    pub fn new(size: &Size, format: Extension, lossless: bool, prefer_quality: bool) -> OptimizationConfig {
         let quality = size.quality[format as usize];
 
         match format {
             Extension::WEBP => OptimizationConfig::Webp {
                 quality,
+                encoder: size.encoder.webp.clone(),
+                lossless,
                 prefer_quality,
             },
+            //libavif can not encode RGB losslessly, graphics are only spared the chroma subsampling
             Extension::AVIF => OptimizationConfig::Avif {
                 quality,
+                encoder: AvifEncoder {
+                    chroma_subsampling: if lossless { Some(ChromaSubsampling::YUV444) } else { size.encoder.avif.chroma_subsampling },
+                    ..size.encoder.avif.clone()
+                },
                 prefer_quality,
             },
             Extension::JPEG => OptimizationConfig::Jpeg {
                 quality,
+                chroma_subsampling: size.chroma_subsampling.unwrap_or(ChromaSubsampling::YUV444),
                 prefer_quality,
             },
         }
@@ -262,14 +847,14 @@ mod tests {
         assert_eq!(config.extensions, vec![Extension::AVIF, Extension::WEBP, Extension::JPEG]);
         assert_eq!(config.default_format, Extension::JPEG);
         assert_eq!(config.roots, vec!["/build/media".to_string()]);
-        assert_eq!(config.url, "/media/{size}/{path}[.{ext}]");
+        assert_eq!(config.routes[0].url, "/media/{size}/{path}[.{ext}]");
         assert_eq!(config.cache_directory, "/build/cache".to_string());
         assert!(config.sizes.contains_key("low"));
         assert!(config.sizes.contains_key("medium"));
         assert!(config.sizes.contains_key("high"));
         assert!(config.sizes.contains_key("product"));
         assert!(config.logger.is_some());
-        assert!(config.url_regex.is_some());
+        assert!(config.routes[0].url_regex.is_some());
     }
 
     #[test]
@@ -329,6 +914,180 @@ mod tests {
         assert_eq!(config.sizes["medium"].quality[Extension::WEBP as usize], Extension::WEBP.default_quality());
         assert_eq!(config.sizes["high"].quality[Extension::AVIF as usize], Extension::AVIF.default_quality());
     }
+
+    #[test]
+    fn test_parse_chroma_subsampling() {
+        let config_content = String::from(r#"
+        (
+            extensions: [AVIF, WEBP, JPEG],
+            default_format: JPEG,
+            roots: ["/build/media"],
+            url: "/media/{size}/{path}[.{ext}]",
+            cache_directory: "/build/cache",
+            sizes: {
+                "low": Size(width: 300, height: 300, chroma_subsampling: YUV420),
+                "high": Size(width: 1200, height: 1200),
+            },
+        )
+        "#);
+
+        let config = Config::parse(config_content).expect("Failed to parse valid config");
+
+        assert_eq!(config.sizes["low"].chroma_subsampling, Some(ChromaSubsampling::YUV420));
+        assert_eq!(config.sizes["high"].chroma_subsampling, None);
+        assert!(matches!(
+            OptimizationConfig::new(&config.sizes["high"], Extension::JPEG, false, false),
+            OptimizationConfig::Jpeg { chroma_subsampling: ChromaSubsampling::YUV444, .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_encoder_overrides() {
+        let config_content = String::from(r#"
+        (
+            extensions: [AVIF, WEBP, JPEG],
+            default_format: JPEG,
+            roots: ["/build/media"],
+            url: "/media/{size}/{path}[.{ext}]",
+            cache_directory: "/build/cache",
+            encoder: (
+                avif: (speed: 4, chroma_subsampling: YUV420),
+                webp: (method: 6, sharp_yuv: true),
+            ),
+            sizes: {
+                "low": Size(width: 300, height: 300, encoder: (avif: (speed: 8))),
+                "high": Size(width: 1200, height: 1200),
+            },
+        )
+        "#);
+
+        let config = Config::parse(config_content).expect("Failed to parse valid config");
+
+        assert_eq!(config.sizes["low"].encoder.avif.speed, Some(8));
+        assert_eq!(config.sizes["low"].encoder.avif.chroma_subsampling, Some(ChromaSubsampling::YUV420));
+        assert_eq!(config.sizes["low"].encoder.webp.method, Some(6));
+        assert_eq!(config.sizes["high"].encoder.avif.speed, Some(4));
+        assert_eq!(config.sizes["high"].encoder.webp.sharp_yuv, Some(true));
+        assert_eq!(config.sizes["high"].encoder.webp.near_lossless, None);
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let config_content = String::from(r#"
+        (
+            extensions: [AVIF, WEBP, JPEG],
+            default_format: JPEG,
+            roots: ["/build/media"],
+            url: "/media/{size}/{path}[.{ext}]",
+            cache_directory: "/build/cache",
+            sizes: {
+                "low": Size(width: 300, height: 300, target: Bytes(20000)),
+                "high": Size(width: 1200, height: 1200, target: Dssim(0.002)),
+                "fixed": Size(width: 600, height: 600),
+            },
+        )
+        "#);
+
+        let config = Config::parse(config_content).expect("Failed to parse valid config");
+
+        assert_eq!(config.sizes["low"].target, Some(Target::Bytes(20000)));
+        assert_eq!(config.sizes["high"].target, Some(Target::Dssim(0.002)));
+        assert_eq!(config.sizes["fixed"].target, None);
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let config_content = String::from(r#"
+        (
+            extensions: [AVIF, WEBP, JPEG],
+            default_format: JPEG,
+            roots: ["/build/media"],
+            url: "/media/{size}/{path}[.{ext}]",
+            cache_directory: "/build/cache",
+            limits: (max_pixels: 40000000, max_file_bytes: 52428800, over_limit: Reject),
+            sizes: {
+                "low": Size(width: 300, height: 300),
+            },
+        )
+        "#);
+
+        let config = Config::parse(config_content).expect("Failed to parse valid config");
+        let limits = config.limits.expect("Limits should be parsed");
+
+        assert_eq!(limits.max_pixels, Some(40_000_000));
+        assert_eq!(limits.max_file_bytes, Some(52_428_800));
+        assert_eq!(limits.max_width, None);
+        assert_eq!(limits.over_limit, Some(OverLimit::Reject));
+    }
+
+    #[test]
+    fn test_parse_invalid_min_savings() {
+        let config_content = String::from(r#"
+        (
+            extensions: [AVIF, WEBP, JPEG],
+            default_format: JPEG,
+            roots: ["/build/media"],
+            url: "/media/{size}/{path}[.{ext}]",
+            cache_directory: "/build/cache",
+            min_savings: 120,
+            sizes: {
+                "low": Size(width: 300, height: 300),
+            },
+        )
+        "#);
+
+        assert!(Config::parse(config_content).is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_encoder() {
+        let config_content = String::from(r#"
+        (
+            extensions: [AVIF, WEBP, JPEG],
+            default_format: JPEG,
+            roots: ["/build/media"],
+            url: "/media/{size}/{path}[.{ext}]",
+            cache_directory: "/build/cache",
+            sizes: {
+                "low": Size(width: 300, height: 300, encoder: (webp: (method: 9))),
+            },
+        )
+        "#);
+
+        let result = Config::parse(config_content);
+        assert!(result.is_err());
+        if let Err(err) = result {
+            assert_eq!(err.to_string(), "Invalid encoder configuration for size low: WEBP method must be between 0 and 6, got 9");
+        }
+    }
+
+    #[test]
+    fn test_outdated_sizes() {
+        let parse = |qualities: &str, min_savings: &str| Config::parse(format!(r#"
+        (
+            extensions: [AVIF, WEBP],
+            default_format: JPEG,
+            roots: ["/build/media"],
+            url: "/media/{{size}}/{{path}}",
+            cache_directory: "/build/cache",
+            {}
+            sizes: {{
+                "low": Size(width: 300, height: 300, qualities: {{AVIF: 30}}),
+                "high": Size(width: 1200, height: 1200, {}),
+            }},
+        )
+        "#, min_savings, qualities)).expect("Failed to parse config");
+
+        let config = parse("", "");
+
+        assert!(config.outdated_sizes(&parse("", "")).is_empty());
+        assert_eq!(config.outdated_sizes(&parse("qualities: {AVIF: 60}", "")), vec![String::from("high")]);
+
+        let mut outdated = config.outdated_sizes(&parse("", "min_savings: 10,"));
+        outdated.sort();
+        assert_eq!(outdated, vec![String::from("high"), String::from("low")]);
+    }
+
     #[test]
     fn test_build_url_regex_valid_pattern() {
         let url = "/media/{size}/{path}[.{ext}]";
@@ -399,6 +1158,171 @@ mod tests {
         assert_eq!(captures.name("ext").unwrap().as_str(), "webp");
     }
 
+    #[test]
+    fn test_build_url() {
+        let mut config = Config {
+            routes: vec![Route::new("/media/[optional/]{size}/{path}[.{ext}]").unwrap()],
+            ..Config::default()
+        };
+
+        assert_eq!(config.build_url("products/monitor", "default", None).unwrap(), "/media/default/products/monitor");
+        assert_eq!(config.build_url("products/monitor", "default", Some("webp")).unwrap(), "/media/default/products/monitor.webp");
+        assert_eq!(config.build_url("products/big monitor", "default", None).unwrap(), "/media/default/products/big%20monitor");
+        assert!(config.build_url("products/monitor", "unknown", None).is_err());
+
+        config.routes = vec![Route::new("/media/{size}/{path}.{ext}").unwrap()];
+        assert_eq!(config.build_url("products/monitor", "default", None).unwrap(), "/media/default/products/monitor.jpg");
+
+        let url = config.build_url("products/monitor", "default", Some("avif")).unwrap();
+        let captures = Config::build_url_regex(&config.routes[0].url).unwrap().captures(&url).unwrap();
+        assert_eq!(&captures["size"], "default");
+        assert_eq!(&captures["path"], "products/monitor");
+    }
+
+    #[test]
+    fn test_build_srcset() {
+        let mut config = Config {
+            routes: vec![Route::new("/media/{size}/{path}").unwrap()],
+            ..Config::default()
+        };
+        let mut large = config.sizes["default"].clone();
+        large.width = 1000;
+        config.sizes.insert(String::from("large"), large);
+
+        assert_eq!(
+            config.build_srcset("products/monitor", "default, large", |_| Ok(None)).unwrap(),
+            "/media/default/products/monitor 500w, /media/large/products/monitor 1000w",
+        );
+        assert!(config.build_srcset("products/monitor", "default,unknown", |_| Ok(None)).is_err());
+
+        //a portrait source is limited by the height of the sizes
+        assert_eq!(
+            config.build_srcset("products/monitor", "default, large", |_| Ok(Some((1000, 2000)))).unwrap(),
+            "/media/default/products/monitor 250w, /media/large/products/monitor 250w",
+        );
+
+        //the source is looked up in the root of the route serving the size
+        let mut archive = Route::new("/archive/{path}").unwrap();
+        archive.size = Some(String::from("large"));
+        archive.root = Some(String::from("/var/www/archive"));
+        config.routes.insert(0, archive);
+        assert_eq!(
+            config.build_srcset("products/monitor", "default, large", |root| Ok(root.is_none().then_some((1000, 2000)))).unwrap(),
+            "/media/default/products/monitor 250w, /archive/products/monitor 1000w",
+        );
+
+        config.sizes.get_mut("large").unwrap().pattern_regex = Some(Regex::new("^banners/").unwrap());
+        assert_eq!(config.build_srcset("products/monitor", "default, large", |_| Ok(None)).unwrap(), "/media/default/products/monitor 500w");
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let config = Config::parse(String::from(r#"
+        (
+            extensions: [AVIF, WEBP],
+            default_format: JPEG,
+            roots: ["/build/media", "/build/products"],
+            routes: [
+                Route(url: "/media/{size}/{path}.{ext}", sizes: ["low", "high"]),
+                Route(url: "/img/{path}?s={size}"),
+                Route(url: "/thumbs/{path}", size: "thumbnail", root: "/build/products"),
+            ],
+            cache_directory: "/build/cache",
+            sizes: {
+                "low": Size(width: 300, height: 300),
+                "high": Size(width: 1200, height: 1200),
+                "thumbnail": Size(width: 100, height: 100),
+            },
+        )
+        "#)).expect("Failed to parse config");
+
+        let image = |size: &str, path: &str, root: Option<&str>| Some(ImageUrl {
+            size: size.to_owned(),
+            path: path.to_owned(),
+            root: root.map(str::to_owned),
+        });
+
+        assert_eq!(config.parse_url("/media/low/products/monitor.avif").unwrap(), image("low", "products/monitor", None));
+        assert_eq!(config.parse_url("/media/thumbnail/products/monitor.avif").unwrap(), None);
+        assert_eq!(config.parse_url("/img/products/monitor?s=thumbnail").unwrap(), image("thumbnail", "products/monitor", None));
+        assert_eq!(config.parse_url("/thumbs/monitor").unwrap(), image("thumbnail", "monitor", Some("/build/products")));
+        assert_eq!(config.parse_url("/other/monitor").unwrap(), None);
+
+        assert_eq!(config.build_url("products/monitor", "thumbnail", None).unwrap(), "/img/products/monitor?s=thumbnail");
+    }
+
+    #[test]
+    fn test_parse_invalid_routes() {
+        let parse = |routes: &str| Config::parse(format!(r#"
+        (
+            extensions: [AVIF],
+            default_format: JPEG,
+            roots: ["/build/media"],
+            routes: [{}],
+            cache_directory: "/build/cache",
+            sizes: {{
+                "low": Size(width: 300, height: 300),
+            }},
+        )
+        "#, routes));
+
+        assert!(parse(r#"Route(url: "/media/{size}/{path}")"#).is_ok());
+        assert!(parse("").is_err());
+        assert!(parse(r#"Route(url: "/media/{path}")"#).is_err());
+        assert!(parse(r#"Route(url: "/media/{size}/{path}", size: "low")"#).is_err());
+        assert!(parse(r#"Route(url: "/media/{path}", size: "high")"#).is_err());
+        assert!(parse(r#"Route(url: "/media/{size}/{path}", sizes: ["low", "high"])"#).is_err());
+        assert!(parse(r#"Route(url: "/media/{path}", size: "low", sizes: ["low"])"#).is_err());
+        assert!(parse(r#"Route(url: "/media/{path}", size: "low", root: "/build/other")"#).is_err());
+        assert!(parse(r#"Route(url: "/media/{path}?size={size}&fmt={ext}")"#).is_ok());
+        assert!(parse(r#"Route(url: "/media/{path}?size={size}&{ext}")"#).is_err());
+        assert!(parse(r#"Route(url: "/media/{path}?size={size}&fmt={other}")"#).is_err());
+        assert!(parse(r#"Route(url: "/media/{path}?size={size}&fmt=.{ext}")"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_query_params() {
+        let mut config = Config::parse(String::from(r#"
+        (
+            extensions: [AVIF, WEBP],
+            default_format: JPEG,
+            roots: ["/build/media"],
+            routes: [
+                Route(url: "/media/{size}/{path}.{ext}"),
+                Route(url: "/img?path={path}&size={size}&[fmt={ext}]&format=auto"),
+            ],
+            cache_directory: "/build/cache",
+            sizes: {
+                "low": Size(width: 300, height: 300),
+            },
+        )
+        "#)).expect("Failed to parse config");
+
+        let image = |path: &str| Some(ImageUrl {
+            size: String::from("low"),
+            path: path.to_owned(),
+            root: None,
+        });
+
+        assert_eq!(config.parse_url("/media/low/products/monitor.avif?v=123").unwrap(), image("products/monitor"));
+        assert_eq!(config.parse_url("/img?path=products/monitor&size=low&format=auto").unwrap(), image("products/monitor"));
+        assert_eq!(config.parse_url("/img?format=auto&fmt=webp&size=low&path=products%2Fbig%20monitor&v=1").unwrap(), image("products/big monitor"));
+        assert_eq!(config.parse_url("/img?path=products/monitor&size=low").unwrap(), None);
+        assert_eq!(config.parse_url("/img?path=products/monitor&size=low&format=manual").unwrap(), None);
+        assert_eq!(config.parse_url("/img?path=products/monitor&size=unknown&format=auto").unwrap(), None);
+        assert_eq!(config.parse_url("/img?path=&size=low&format=auto").unwrap(), None);
+
+        config.unknown_query_params = Some(UnknownParams::Reject);
+        assert_eq!(config.parse_url("/media/low/products/monitor.avif?v=123").unwrap(), None);
+        assert_eq!(config.parse_url("/media/low/products/monitor.avif").unwrap(), image("products/monitor"));
+        assert_eq!(config.parse_url("/img?path=products/monitor&size=low&format=auto&v=1").unwrap(), None);
+        assert_eq!(config.parse_url("/img?path=products/monitor&size=low&format=auto&fmt=webp").unwrap(), image("products/monitor"));
+
+        config.routes.remove(0);
+        assert_eq!(config.build_url("products/monitor", "low", None).unwrap(), "/img?path=products/monitor&size=low&format=auto");
+        assert_eq!(config.build_url("products/monitor", "low", Some("webp")).unwrap(), "/img?path=products/monitor&size=low&fmt=webp&format=auto");
+    }
+
     #[test]
     fn test_build_url_regex_invalid_pattern_missing_path() {
         let url = "/media/{size}//[.{ext}]";
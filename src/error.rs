@@ -8,6 +8,7 @@ pub enum Error {
     Custom(String),
     Poison(String),
     Send(String),
+    LimitExceeded(String),
     Other(Box<dyn StdError>),
 }
 
@@ -35,6 +36,7 @@ impl fmt::Display for Error {
             Error::Custom(s) => write!(f, "{}", s),
             Error::Poison(s) => write!(f, "{}", s),
             Error::Send(s) => write!(f, "{}", s),
+            Error::LimitExceeded(s) => write!(f, "{}", s),
             Error::Other(s) => write!(f, "{}", s),
         }
     }
@@ -77,11 +79,16 @@ impl<T> From<SendError<T>> for Error {
 }
 
 error_from!(Error::Other, bx ron::error::SpannedError);
+error_from!(Error::Other, bx serde_json::Error);
 error_from!(Error::Other, bx std::string::FromUtf8Error);
 error_from!(Error::Other, bx regex::Error);
 error_from!(Error::Other, bx libavif::Error);
-error_from!(Error::Other, bx turbojpeg::Error);
+error_from!(Error::Other, bx libheif_rs::HeifError);
+error_from!(Error::Other, bx resvg::usvg::Error);
+error_from!(Error::Other, bx roxmltree::Error);
 error_from!(Error::Other, bx image::ImageError);
+error_from!(Error::Other, bx fast_image_resize::ResizeError);
+error_from!(Error::Other, bx fast_image_resize::ImageBufferError);
 error_from!(Error::Other, bx std::io::Error);
 error_from!(Error::Other, bx varnish::vcl::Error);
 
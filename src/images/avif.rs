@@ -1,5 +1,6 @@
-use image::DynamicImage;
+use image::{DynamicImage, RgbaImage};
 use libavif::{AvifData, AvifImage, Encoder, RgbPixels, YuvFormat};
+use crate::config::{AvifEncoder, ChromaSubsampling};
 use crate::error::Error;
 use crate::images::OptimizedImage;
 
@@ -11,6 +12,14 @@ impl OptimizedImage for Avif {
     fn data(&self) -> &[u8] {
         self.data.as_slice()
     }
+
+    fn decode(&self) -> Result<DynamicImage, Error> {
+        let pixels = libavif::decode_rgb(self.data())?;
+
+        RgbaImage::from_raw(pixels.width(), pixels.height(), pixels.to_vec())
+            .map(DynamicImage::ImageRgba8)
+            .ok_or_else(|| Error::new("Invalid avif pixel data"))
+    }
 }
 
 impl Into<Avif> for AvifData<'static> {
@@ -21,7 +30,15 @@ impl Into<Avif> for AvifData<'static> {
     }
 }
 
-pub fn to_avif(image: &DynamicImage, quality: f32, prefer_quality: bool) -> Result<Avif, Error> {
+pub fn to_avif(image: &DynamicImage, quality: f32, encoder: &AvifEncoder, prefer_quality: bool) -> Result<Avif, Error> {
+    let alpha_quality = encoder.alpha_quality.unwrap_or(50);
+
+    let yuv_format = match encoder.chroma_subsampling.unwrap_or(ChromaSubsampling::YUV444) {
+        ChromaSubsampling::YUV444 => YuvFormat::Yuv444,
+        ChromaSubsampling::YUV422 => YuvFormat::Yuv422,
+        ChromaSubsampling::YUV420 => YuvFormat::Yuv420,
+    };
+
     let image = {
         let width = image.width();
         let height = image.height();
@@ -30,15 +47,15 @@ pub fn to_avif(image: &DynamicImage, quality: f32, prefer_quality: bool) -> Resu
         if (width * height) as usize == data.len() {
             AvifImage::from_luma8(width, height, data)?
         } else {
-            RgbPixels::new(width, height, data)?.to_image(YuvFormat::Yuv444)
+            RgbPixels::new(width, height, data)?.to_image(yuv_format)
         }
     };
 
     Ok(Encoder::new()
         .set_quality(quality as u8) //TODO: allow different quality for avif and webp, 40
-        .set_alpha_quality(50)
-        .set_max_threads(1)
-        .set_speed(if prefer_quality { 0 } else { 6 })
+        .set_alpha_quality(alpha_quality)
+        .set_max_threads(encoder.max_threads.unwrap_or(1))
+        .set_speed(encoder.speed.unwrap_or(if prefer_quality { 0 } else { 6 }))
         .encode(&image)?
         .into())
 }
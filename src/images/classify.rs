@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+use image::DynamicImage;
+use itertools::Itertools;
+
+/// Palette images are always treated as graphics
+const MAX_GRAPHIC_COLORS: usize = 256;
+/// Share of pixels identical to their right neighbour above which the image is made of flat areas
+const MIN_FLAT_RATIO: f64 = 0.5;
+/// Share of semi transparent pixels above which alpha is considered to be smooth like in cutout photos
+const MAX_PARTIAL_ALPHA_RATIO: f64 = 0.05;
+
+/// Screenshots, logos and other flat color graphics compress better and look
+/// sharper with lossless encoders, photos get better results with lossy ones
+pub fn is_graphic(image: &DynamicImage) -> bool {
+    let pixels = image.to_rgba8();
+    let total = pixels.width() as f64 * pixels.height() as f64;
+
+    if total == 0.0 {
+        return false;
+    }
+
+    let mut colors = HashSet::new();
+    for pixel in pixels.pixels() {
+        colors.insert(pixel.0);
+
+        if colors.len() > MAX_GRAPHIC_COLORS {
+            break;
+        }
+    }
+
+    if colors.len() <= MAX_GRAPHIC_COLORS {
+        return true;
+    }
+
+    let partial_alpha = pixels.pixels()
+        .filter(|pixel| pixel.0[3] != 0 && pixel.0[3] != 255)
+        .count() as f64;
+
+    let flat = pixels.rows()
+        .map(|row| row.tuple_windows().filter(|(left, right)| left == right).count())
+        .sum::<usize>() as f64;
+
+    flat / total > MIN_FLAT_RATIO && partial_alpha / total < MAX_PARTIAL_ALPHA_RATIO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{RgbImage, RgbaImage};
+
+    #[test]
+    fn test_flat_graphic() {
+        let image = RgbImage::from_fn(64, 64, |x, _| if x < 32 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) });
+
+        assert!(is_graphic(&DynamicImage::ImageRgb8(image)));
+    }
+
+    #[test]
+    fn test_screenshot_with_many_colors() {
+        //flat background with a gradient banner using more than 256 colors
+        let image = RgbImage::from_fn(128, 128, |x, y| if y < 24 { image::Rgb([x as u8, y as u8 * 10, 0]) } else { image::Rgb([240, 240, 240]) });
+
+        assert!(is_graphic(&DynamicImage::ImageRgb8(image)));
+    }
+
+    #[test]
+    fn test_photo_like_gradient() {
+        let image = RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8 * 4, y as u8 * 4, (x + y) as u8]));
+
+        assert!(!is_graphic(&DynamicImage::ImageRgb8(image)));
+    }
+
+    #[test]
+    fn test_soft_alpha_is_not_graphic() {
+        //flat rows but every pixel is semi transparent
+        let image = RgbaImage::from_fn(64, 512, |_, y| image::Rgba([y as u8, (y / 2) as u8, 0, 128]));
+
+        assert!(!is_graphic(&DynamicImage::ImageRgba8(image)));
+    }
+}
@@ -0,0 +1,98 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use image::{DynamicImage, RgbImage, RgbaImage};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+use crate::error::Error;
+
+pub const EXTENSIONS: [&str; 2] = ["heic", "heif"];
+
+pub fn mime_type<T>(path: T) -> Option<&'static str> where T: AsRef<Path> {
+    let extension = path.as_ref().extension().and_then(OsStr::to_str)?;
+
+    match extension.to_lowercase().as_str() {
+        "heic" => Some("image/heic"),
+        "heif" => Some("image/heif"),
+        _ => None,
+    }
+}
+
+pub fn is_heif<T>(path: T) -> bool where T: AsRef<Path> {
+    mime_type(path).is_some()
+}
+
+pub fn dimensions<T>(path: T) -> Result<(u32, u32), Error> where T: AsRef<Path> {
+    let path = path.as_ref().to_str().ok_or_else(|| Error::new("Invalid HEIF file path"))?;
+    let handle = HeifContext::read_from_file(path)?.primary_image_handle()?;
+
+    Ok((handle.width(), handle.height()))
+}
+
+pub fn read<T>(path: T) -> Result<DynamicImage, Error> where T: AsRef<Path> {
+    let path = path.as_ref().to_str().ok_or_else(|| Error::new("Invalid HEIF file path"))?;
+    let context = HeifContext::read_from_file(path)?;
+    let handle = context.primary_image_handle()?;
+    let has_alpha = handle.has_alpha_channel();
+
+    let chroma = if has_alpha { RgbChroma::Rgba } else { RgbChroma::Rgb };
+    let image = LibHeif::new().decode(&handle, ColorSpace::Rgb(chroma), None)?;
+
+    let planes = image.planes();
+    let plane = planes.interleaved.ok_or_else(|| Error::new("Failed to get HEIF pixel data"))?;
+
+    //rows may be padded, only keep the actual pixels of each row
+    let row_length = plane.width as usize * if has_alpha { 4 } else { 3 };
+    let pixels = plane.data.chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_length])
+        .copied()
+        .collect::<Vec<u8>>();
+
+    let image = if has_alpha {
+        RgbaImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8)
+    };
+
+    image.ok_or_else(|| Error::new("Invalid HEIF pixel data"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //256x256 HEVC image with an alpha plane
+    const BADGE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/badge.heic");
+
+    #[test]
+    fn test_heic_mime_type() {
+        assert_eq!(mime_type("photos/IMG_0001.heic"), Some("image/heic"));
+        assert_eq!(mime_type("photos/IMG_0001.HEIC"), Some("image/heic"));
+    }
+
+    #[test]
+    fn test_heif_mime_type() {
+        assert_eq!(mime_type("/build/media/image.heif"), Some("image/heif"));
+    }
+
+    #[test]
+    fn test_dimensions() {
+        assert_eq!(dimensions(BADGE).unwrap(), (256, 256));
+    }
+
+    #[test]
+    fn test_read_strips_row_padding() {
+        let image = read(BADGE).unwrap();
+
+        assert_eq!((image.width(), image.height()), (256, 256));
+        assert!(matches!(image, DynamicImage::ImageRgba8(_)));
+        assert_eq!(image.as_bytes().len(), 256 * 256 * 4);
+        assert!(image.to_rgba8().pixels().any(|pixel| pixel.0[3] < 255));
+    }
+
+    #[test]
+    fn test_other_formats_are_not_heif() {
+        assert!(!is_heif("photo.jpeg"));
+        assert!(!is_heif("products/monitor.webp"));
+        assert!(!is_heif("heic"));
+    }
+}
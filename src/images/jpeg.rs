@@ -1,19 +1,28 @@
-use image::{DynamicImage, EncodableLayout};
-use turbojpeg::{OwnedBuf, Subsamp};
+use std::ffi::OsStr;
+use std::panic;
+use std::path::Path;
+use image::{DynamicImage, ImageFormat, RgbImage};
+use mozjpeg::{ColorSpace, Compress, Decompress};
+use crate::config::ChromaSubsampling;
 use crate::error::Error;
+use crate::images;
 use crate::images::OptimizedImage;
 
 pub struct Jpeg {
-    data: OwnedBuf,
+    data: Vec<u8>,
 }
 
 impl OptimizedImage for Jpeg {
     fn data(&self) -> &[u8] {
-        self.data.as_bytes()
+        self.data.as_slice()
+    }
+
+    fn decode(&self) -> Result<DynamicImage, Error> {
+        Ok(image::load_from_memory_with_format(self.data(), ImageFormat::Jpeg)?)
     }
 }
 
-impl Into<Jpeg> for OwnedBuf {
+impl Into<Jpeg> for Vec<u8> {
     fn into(self) -> Jpeg {
         Jpeg {
             data: self,
@@ -21,11 +30,150 @@ impl Into<Jpeg> for OwnedBuf {
     }
 }
 
-pub fn to_jpeg(image: &DynamicImage, quality: f32, _prefer_quality: bool) -> Result<Jpeg, Error> {
-    match image {
-        DynamicImage::ImageRgb8(image) => Ok(turbojpeg::compress_image(image, quality as i32, Subsamp::None)?.into()),
-        DynamicImage::ImageRgba8(image) => Ok(turbojpeg::compress_image(image, quality as i32, Subsamp::None)?.into()),
-        _ => Error::err("Unsupported image format"),
+pub fn is_jpeg<T>(path: T) -> bool where T: AsRef<Path> {
+    path.as_ref()
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"))
+}
+
+/// Decodes the JPEG at the smallest `n/8` scale that still covers the bounds once resized,
+/// scaling is done in the DCT domain which is a lot cheaper than decoding the full image
+pub fn read_scaled<T>(path: T, max_width: u32, max_height: u32) -> Result<DynamicImage, Error> where T: AsRef<Path> {
+    let path = path.as_ref();
+
+    //mozjpeg reports errors by unwinding
+    let decoded = panic::catch_unwind(|| {
+        let mut decompress = Decompress::new_path(path)?;
+        let (width, height) = decompress.size();
+        decompress.scale(scale_numerator(width as u32, height as u32, max_width, max_height));
+
+        let mut decompress = decompress.rgb()?;
+        let (width, height) = (decompress.width() as u32, decompress.height() as u32);
+        let pixels = decompress.read_scanlines::<u8>()?;
+        decompress.finish()?;
+
+        Ok::<_, std::io::Error>((width, height, pixels))
+    });
+
+    match decoded {
+        Ok(decoded) => {
+            let (width, height, pixels) = decoded?;
+
+            RgbImage::from_raw(width, height, pixels)
+                .map(DynamicImage::ImageRgb8)
+                .ok_or_else(|| Error::new("Invalid jpeg pixel data"))
+        },
+        Err(_) => Error::err("Failed to decode jpeg"),
     }
+}
 
+fn scale_numerator(width: u32, height: u32, max_width: u32, max_height: u32) -> u8 {
+    let (target_width, target_height) = images::fit_dimensions(width, height, max_width, max_height);
+
+    (1..8)
+        .find(|numerator| {
+            //libjpeg rounds scaled dimensions up
+            (width * numerator).div_ceil(8) >= target_width && (height * numerator).div_ceil(8) >= target_height
+        })
+        .unwrap_or(8) as u8
+}
+
+pub fn to_jpeg(image: &DynamicImage, quality: f32, chroma_subsampling: ChromaSubsampling, prefer_quality: bool) -> Result<Jpeg, Error> {
+    let color_space = match image {
+        DynamicImage::ImageRgb8(_) => ColorSpace::JCS_EXT_RGB,
+        DynamicImage::ImageRgba8(_) => ColorSpace::JCS_EXT_RGBA,
+        _ => return Error::err("Unsupported image format"),
+    };
+
+    let chroma_pixel_size = match chroma_subsampling {
+        ChromaSubsampling::YUV444 => (1, 1),
+        ChromaSubsampling::YUV422 => (2, 1),
+        ChromaSubsampling::YUV420 => (2, 2),
+    };
+
+    //mozjpeg reports errors by unwinding, they have to be caught
+    //to avoid taking down the whole varnish process
+    let encoded = panic::catch_unwind(|| {
+        let mut compress = Compress::new(color_space);
+        compress.set_size(image.width() as usize, image.height() as usize);
+        compress.set_quality(quality);
+        compress.set_chroma_sampling_pixel_sizes(chroma_pixel_size, chroma_pixel_size);
+
+        //trellis quantization is enabled by mozjpeg's default profile
+        compress.set_progressive_mode();
+        compress.set_optimize_coding(true);
+        compress.set_optimize_scans(true);
+        compress.set_use_scans_in_trellis(prefer_quality);
+
+        let mut compress = compress.start_compress(Vec::new())?;
+        compress.write_scanlines(image.as_bytes())?;
+        compress.finish()
+    });
+
+    match encoded {
+        Ok(data) => Ok(data?.into()),
+        Err(_) => Error::err("Failed to encode jpeg"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use image::RgbImage;
+    use crate::utils;
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 128])))
+    }
+
+    #[test]
+    fn test_to_jpeg_is_progressive() {
+        let jpeg = to_jpeg(&gradient(), 80.0, ChromaSubsampling::YUV420, false).expect("Failed to encode jpeg");
+
+        //SOF2 marker, progressive DCT with huffman coding
+        assert!(jpeg.data().windows(2).any(|marker| marker == [0xFF, 0xC2]));
+
+        let decoded = image::load_from_memory(jpeg.data()).expect("Failed to decode jpeg");
+        assert_eq!((decoded.width(), decoded.height()), (64, 48));
+    }
+
+    #[test]
+    fn test_to_jpeg_subsampling_reduces_size() {
+        let full = to_jpeg(&gradient(), 90.0, ChromaSubsampling::YUV444, false).expect("Failed to encode jpeg");
+        let subsampled = to_jpeg(&gradient(), 90.0, ChromaSubsampling::YUV420, false).expect("Failed to encode jpeg");
+
+        assert!(subsampled.data().len() < full.data().len());
+    }
+
+    #[test]
+    fn test_scale_numerator() {
+        //6000x4000 source to a 300px thumbnail, 1/8 is 750x500
+        assert_eq!(scale_numerator(6000, 4000, 300, 300), 1);
+        //1200x800 to 600x600 needs 600x400, 4/8 is exactly that
+        assert_eq!(scale_numerator(1200, 800, 600, 600), 4);
+        //upscaling never scales the decoding
+        assert_eq!(scale_numerator(200, 100, 600, 600), 8);
+    }
+
+    #[test]
+    fn test_read_scaled() {
+        let jpeg = to_jpeg(&gradient(), 90.0, ChromaSubsampling::YUV444, false).expect("Failed to encode jpeg");
+        let path = PathBuf::from(utils::temp_dir("read_scaled.jpeg"));
+        std::fs::write(&path, jpeg.data()).unwrap();
+
+        let image = read_scaled(&path, 16, 16).expect("Failed to decode jpeg");
+
+        //64x48 source fits in 16x12, 2/8 of the source
+        assert_eq!((image.width(), image.height()), (16, 12));
+        assert!(is_jpeg(&path));
+    }
+
+    #[test]
+    fn test_to_jpeg_unsupported_format() {
+        let image = DynamicImage::ImageLuma16(image::ImageBuffer::new(4, 4));
+
+        assert!(to_jpeg(&image, 80.0, ChromaSubsampling::YUV444, false).is_err());
+    }
 }
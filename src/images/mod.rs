@@ -1,18 +1,114 @@
 mod avif;
+mod classify;
+mod heif;
 mod webp;
 mod jpeg;
+mod resize;
+mod similarity;
+pub mod svg;
 
+use std::collections::HashSet;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Cursor, Write};
+use std::ops::Deref;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::SystemTime;
-use image::DynamicImage;
-use image::imageops::FilterType;
+use image::{DynamicImage, ImageError, ImageFormat};
+use crate::config::{AvifEncoder, ChromaSubsampling, Limits, Target, WebpEncoder};
 use crate::error::Error;
+use crate::images::similarity::Similarity;
+
+pub use classify::is_graphic;
+
+const MIN_QUALITY: u8 = 1;
+const MAX_QUALITY: u8 = 100;
+
+/// Makes the names of the temporary files unique among the threads of the process
+static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+
+pub fn supported_extensions() -> HashSet<&'static str> {
+    ImageFormat::all()
+        .flat_map(ImageFormat::extensions_str)
+        .chain(heif::EXTENSIONS.iter())
+        .chain(svg::EXTENSIONS.iter())
+        .map(Deref::deref)
+        .collect()
+}
+
+pub fn mime_type<T>(path: T) -> Result<&'static str, Error> where T: AsRef<Path> {
+    if let Some(mime) = heif::mime_type(&path) {
+        return Ok(mime);
+    }
+
+    if svg::is_svg(&path) {
+        return Ok(svg::MIME_TYPE);
+    }
+
+    Ok(ImageFormat::from_path(path)?.to_mime_type())
+}
+
+/// Reads the dimensions of the image from its header
+pub fn dimensions<T>(path: T) -> Result<(u32, u32), Error> where T: AsRef<Path> {
+    if heif::is_heif(&path) {
+        heif::dimensions(path)
+    } else {
+        Ok(image::io::Reader::open(path)?.with_guessed_format()?.into_dimensions()?)
+    }
+}
+
+/// Checks the source against the limits without decoding it, only reading its header
+pub fn check_limits<T>(path: T, limits: &Limits) -> Result<(), Error> where T: AsRef<Path> {
+    let path = path.as_ref();
+
+    let length = fs::metadata(path)?.len();
+    if limits.max_file_bytes.is_some_and(|max| length > max) {
+        return Err(Error::LimitExceeded(format!("{} is {} bytes", path.to_string_lossy(), length)));
+    }
+
+    //svgs are rasterized straight at the requested size
+    if svg::is_svg(path) {
+        return Ok(());
+    }
+
+    let (width, height) = dimensions(path)?;
+
+    let pixels = width as u64 * height as u64;
+    let exceeded = limits.max_width.is_some_and(|max| width > max)
+        || limits.max_height.is_some_and(|max| height > max)
+        || limits.max_pixels.is_some_and(|max| pixels > max)
+        || limits.max_memory.is_some_and(|max| pixels * 4 > max); //decoded as RGBA8 at most
+
+    if exceeded {
+        return Err(Error::LimitExceeded(format!("{} is {}x{}", path.to_string_lossy(), width, height)));
+    }
+
+    Ok(())
+}
+
+/// Reads the image, when bounds are given the image may be decoded at a lower
+/// resolution as long as it still is large enough to be resized to the bounds
+pub fn read<T>(path: T, bounds: Option<(u32, u32)>, limits: Option<&Limits>) -> Result<DynamicImage, Error> where T: AsRef<Path> {
+    if let Some(limits) = limits {
+        check_limits(&path, limits)?;
+    }
+
+    let image = if heif::is_heif(&path) {
+        heif::read(path)?
+    } else if let (true, Some((max_width, max_height))) = (jpeg::is_jpeg(&path), bounds) {
+        match jpeg::read_scaled(&path, max_width, max_height) {
+            Ok(image) => image,
+            Err(error) => {
+                //CMYK and other exotic jpegs are left to the image crate
+                debug!("Scaled decoding of {} failed: {}", path.as_ref().to_string_lossy(), error);
+                decode(path, limits)?
+            }
+        }
+    } else {
+        decode(path, limits)?
+    };
 
-pub fn read<T>(path: T) -> Result<DynamicImage, Error> where T: AsRef<Path> {
-    let image = image::open(path)?;
     if matches!(&image, DynamicImage::ImageRgb8(_)) || matches!(&image, DynamicImage::ImageRgba8(_)) {
         Ok(image)
     } else {
@@ -20,41 +116,299 @@ pub fn read<T>(path: T) -> Result<DynamicImage, Error> where T: AsRef<Path> {
     }
 }
 
-pub fn resize(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
-    image.resize(width, height, FilterType::Lanczos3)
+/// Decodes with the image crate, which also enforces the limits while decoding in case the header lied
+fn decode<T>(path: T, limits: Option<&Limits>) -> Result<DynamicImage, Error> where T: AsRef<Path> {
+    let mut reader = image::io::Reader::open(path)?.with_guessed_format()?;
+
+    if let Some(limits) = limits {
+        let mut decoder_limits = image::io::Limits::default();
+        decoder_limits.max_image_width = limits.max_width;
+        decoder_limits.max_image_height = limits.max_height;
+        decoder_limits.max_alloc = limits.max_memory.or(decoder_limits.max_alloc);
+
+        reader.limits(decoder_limits);
+    }
+
+    reader.decode().map_err(|error| match error {
+        ImageError::Limits(error) => Error::LimitExceeded(error.to_string()),
+        error => error.into(),
+    })
+}
+
+pub fn resize(image: &DynamicImage, width: u32, height: u32, linear: bool) -> DynamicImage {
+    resize::resize(image, width, height, linear)
 }
 
-pub fn optimize(image: &DynamicImage, config: OptimizationConfig) -> Result<Box<dyn OptimizedImage>, Error> {
+pub fn resizer() -> String {
+    resize::implementation()
+}
+
+/// Dimensions of an image once resized to fit in the given bounds, keeping its aspect ratio
+pub fn fit_dimensions(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let ratio = f64::min(max_width as f64 / width as f64, max_height as f64 / height as f64);
+
+    (
+        ((width as f64 * ratio).round() as u32).max(1),
+        ((height as f64 * ratio).round() as u32).max(1),
+    )
+}
+
+/// Encodes the image with the configured quality, or when a target is given, binary
+/// searches the lowest quality reaching the similarity target or the highest quality
+/// fitting in the byte budget
+pub fn optimize(image: &DynamicImage, config: OptimizationConfig, target: Option<Target>) -> Result<Optimized, Error> {
+    //lossless encoders do not trade quality for size
+    let Some(target) = target.filter(|_| !config.is_lossless()) else {
+        return Ok(Optimized {
+            quality: config.quality(),
+            image: encode(image, config)?,
+        });
+    };
+
+    let similarity = match target {
+        Target::Dssim(_) => Some(Similarity::new(image)?),
+        Target::Bytes(_) => None,
+    };
+
+    let mut low = MIN_QUALITY;
+    let mut high = MAX_QUALITY;
+    let mut best = None;
+
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let encoded = encode(image, config.with_quality(quality as f32))?;
+
+        let meets_target = match target {
+            Target::Bytes(max) => encoded.data().len() <= max,
+            Target::Dssim(max) => similarity.as_ref()
+                .expect("Logic error: the reference image should be prepared")
+                .dssim(&encoded.decode()?)? <= max,
+        };
+
+        //higher qualities get closer to the original but also heavier
+        let search_higher = match target {
+            Target::Dssim(_) => !meets_target,
+            Target::Bytes(_) => meets_target,
+        };
+
+        if meets_target {
+            best = Some(Optimized { image: encoded, quality: quality as f32 });
+        }
+
+        if search_higher {
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+    }
+
+    match best {
+        Some(optimized) => Ok(optimized),
+        None => {
+            //the target can not be met, get as close as possible to it
+            let quality = match target {
+                Target::Dssim(_) => MAX_QUALITY,
+                Target::Bytes(_) => MIN_QUALITY,
+            } as f32;
+
+            Ok(Optimized {
+                image: encode(image, config.with_quality(quality))?,
+                quality,
+            })
+        }
+    }
+}
+
+fn encode(image: &DynamicImage, config: OptimizationConfig) -> Result<Box<dyn OptimizedImage>, Error> {
     let optimized: Box<dyn OptimizedImage> = match config {
-        OptimizationConfig::Webp { quality, prefer_quality } => Box::new(webp::to_webp(&image, quality, prefer_quality)?),
-        OptimizationConfig::Avif { quality, prefer_quality } => Box::new(avif::to_avif(&image, quality, prefer_quality)?),
-        OptimizationConfig::Jpeg { quality, prefer_quality } => Box::new(jpeg::to_jpeg(&image, quality, prefer_quality)?),
+        OptimizationConfig::Webp { quality, encoder, lossless, prefer_quality } => Box::new(webp::to_webp(&image, quality, &encoder, lossless, prefer_quality)?),
+        OptimizationConfig::Avif { quality, encoder, prefer_quality } => Box::new(avif::to_avif(&image, quality, &encoder, prefer_quality)?),
+        OptimizationConfig::Jpeg { quality, chroma_subsampling, prefer_quality } => Box::new(jpeg::to_jpeg(&image, quality, chroma_subsampling, prefer_quality)?),
     };
 
     Ok(optimized)
 }
 
+/// Lossless encoding of the resized PNG sources
+pub fn to_png(image: &DynamicImage) -> Result<Vec<u8>, Error> {
+    let mut data = Cursor::new(Vec::new());
+    image.write_to(&mut data, ImageFormat::Png)?;
+
+    Ok(data.into_inner())
+}
+
+/// Writes to a temporary file that is renamed once complete, so readers never get a partial
+/// file and concurrent writers of the same file do not fail, the last one wins
 pub fn write<T>(path: T, data: &[u8], last_modified: Option<SystemTime>) -> Result<(), Error> where T: AsRef<Path> {
-    let directory = path.as_ref().parent().expect("Logic error: file should be in a directory");
+    let path = path.as_ref();
+    let directory = path.parent().expect("Logic error: file should be in a directory");
 
     fs::create_dir_all(directory)?;
 
-    let mut file = File::create_new(path)?;
-    file.write(data)?;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.{}.tmp", std::process::id(), TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)));
 
-    if let Some(last_modified) = last_modified {
-        file.set_modified(last_modified)?;
+    let result = File::create_new(&temporary)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+
+            if let Some(last_modified) = last_modified {
+                file.set_modified(last_modified)?;
+            }
+
+            Ok(())
+        })
+        .and_then(|_| fs::rename(&temporary, path));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
     }
 
-    Ok(())
+    Ok(result?)
 }
 
+#[derive(Clone)]
 pub enum OptimizationConfig {
-    Webp { quality: f32, prefer_quality: bool },
-    Avif { quality: f32, prefer_quality: bool },
-    Jpeg { quality: f32, prefer_quality: bool },
+    Webp { quality: f32, encoder: WebpEncoder, lossless: bool, prefer_quality: bool },
+    Avif { quality: f32, encoder: AvifEncoder, prefer_quality: bool },
+    Jpeg { quality: f32, chroma_subsampling: ChromaSubsampling, prefer_quality: bool },
+}
+
+impl OptimizationConfig {
+    pub fn quality(&self) -> f32 {
+        match self {
+            OptimizationConfig::Webp { quality, .. } => *quality,
+            OptimizationConfig::Avif { quality, .. } => *quality,
+            OptimizationConfig::Jpeg { quality, .. } => *quality,
+        }
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        match self {
+            OptimizationConfig::Webp { lossless, .. } => *lossless,
+            OptimizationConfig::Avif { .. } | OptimizationConfig::Jpeg { .. } => false,
+        }
+    }
+
+    pub fn with_quality(&self, quality: f32) -> OptimizationConfig {
+        let mut config = self.clone();
+        match &mut config {
+            OptimizationConfig::Webp { quality: q, .. } => *q = quality,
+            OptimizationConfig::Avif { quality: q, .. } => *q = quality,
+            OptimizationConfig::Jpeg { quality: q, .. } => *q = quality,
+        }
+
+        config
+    }
+}
+
+pub struct Optimized {
+    pub image: Box<dyn OptimizedImage>,
+    pub quality: f32,
 }
 
 pub trait OptimizedImage {
     fn data(&self) -> &[u8];
+
+    fn decode(&self) -> Result<DynamicImage, Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use crate::config::Extension;
+    use crate::utils;
+
+    fn noise() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            let value = (x * 7919 + y * 104729) % 251;
+            image::Rgb([value as u8, (value * 3 % 256) as u8, (x * 4) as u8])
+        }))
+    }
+
+    fn jpeg_config() -> OptimizationConfig {
+        OptimizationConfig::Jpeg { quality: 90.0, chroma_subsampling: ChromaSubsampling::YUV444, prefer_quality: false }
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/cutout.png");
+
+        assert!(check_limits(path, &Limits::default()).is_ok());
+        assert!(check_limits(path, &Limits { max_pixels: Some(64 * 64), ..Limits::default() }).is_ok());
+        assert!(matches!(check_limits(path, &Limits { max_pixels: Some(64 * 63), ..Limits::default() }), Err(Error::LimitExceeded(_))));
+        assert!(matches!(check_limits(path, &Limits { max_width: Some(32), ..Limits::default() }), Err(Error::LimitExceeded(_))));
+        assert!(matches!(check_limits(path, &Limits { max_file_bytes: Some(100), ..Limits::default() }), Err(Error::LimitExceeded(_))));
+        assert!(matches!(check_limits(path, &Limits { max_memory: Some(1024), ..Limits::default() }), Err(Error::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let path = Path::new(&utils::temp_dir("write")).join("concurrent.txt");
+        let _ = fs::remove_file(&path);
+
+        let writers = (0..8)
+            .map(|_| std::thread::spawn({
+                let path = path.clone();
+                move || write(&path, b"sanitized", None).is_ok()
+            }))
+            .collect::<Vec<_>>();
+
+        for writer in writers {
+            assert!(writer.join().unwrap());
+        }
+
+        assert_eq!(fs::read(&path).unwrap(), b"sanitized");
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_fit_dimensions() {
+        assert_eq!(fit_dimensions(2000, 1000, 600, 600), (600, 300));
+        assert_eq!(fit_dimensions(1000, 2000, 546, 302), (151, 302));
+        assert_eq!(fit_dimensions(100, 100, 300, 200), (200, 200));
+    }
+
+    #[test]
+    fn test_is_lossless() {
+        let config = OptimizationConfig::Webp { quality: 70.0, encoder: WebpEncoder::default(), lossless: true, prefer_quality: false };
+
+        assert!(config.is_lossless());
+        assert!(!jpeg_config().is_lossless());
+
+        let mut size = crate::config::Config::default().sizes["default"].clone();
+        size.encoder.avif.chroma_subsampling = Some(ChromaSubsampling::YUV420);
+
+        let config = OptimizationConfig::new(&size, Extension::AVIF, true, false);
+        assert!(!config.is_lossless());
+        assert!(matches!(config, OptimizationConfig::Avif { encoder: AvifEncoder { chroma_subsampling: Some(ChromaSubsampling::YUV444), .. }, .. }));
+    }
+
+    #[test]
+    fn test_optimize_without_target_uses_configured_quality() {
+        let optimized = optimize(&noise(), jpeg_config(), None).unwrap();
+
+        assert_eq!(optimized.quality, 90.0);
+    }
+
+    #[test]
+    fn test_optimize_byte_budget() {
+        let full = optimize(&noise(), jpeg_config().with_quality(100.0), None).unwrap();
+        let budget = full.image.data().len() / 2;
+
+        let optimized = optimize(&noise(), jpeg_config(), Some(Target::Bytes(budget))).unwrap();
+
+        assert!(optimized.image.data().len() <= budget);
+        assert!(optimized.quality < 100.0);
+    }
+
+    #[test]
+    fn test_optimize_dssim_target() {
+        let strict = optimize(&noise(), jpeg_config(), Some(Target::Dssim(0.001))).unwrap();
+        let loose = optimize(&noise(), jpeg_config(), Some(Target::Dssim(0.05))).unwrap();
+
+        assert!(loose.quality < strict.quality);
+        assert!(Similarity::new(&noise()).unwrap().dssim(&loose.image.decode().unwrap()).unwrap() <= 0.05);
+    }
 }
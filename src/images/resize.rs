@@ -0,0 +1,211 @@
+use fast_image_resize::images::Image;
+use fast_image_resize::{FilterType as SimdFilterType, PixelType, ResizeAlg, ResizeOptions, Resizer};
+use image::{DynamicImage, ImageBuffer, Rgba, RgbImage, RgbaImage};
+use image::imageops::FilterType;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::error::Error;
+use crate::images;
+
+static SIMD_RESIZES: AtomicU64 = AtomicU64::new(0);
+static LINEAR_RESIZES: AtomicU64 = AtomicU64::new(0);
+static FALLBACK_RESIZES: AtomicU64 = AtomicU64::new(0);
+
+/// Name of the implementation used to resize images, the SIMD resizer picks the
+/// best CPU extensions available at runtime. The number of images each path resized
+/// since startup tells whether the SIMD resizer is really the one at work
+pub fn implementation() -> String {
+    format!(
+        "fast_image_resize ({:?}), {} SIMD, {} linear, {} fallback",
+        Resizer::new().cpu_extensions(),
+        SIMD_RESIZES.load(Ordering::Relaxed),
+        LINEAR_RESIZES.load(Ordering::Relaxed),
+        FALLBACK_RESIZES.load(Ordering::Relaxed),
+    )
+}
+
+pub fn resize(image: &DynamicImage, width: u32, height: u32, linear: bool) -> DynamicImage {
+    //the SIMD resizer works on 8 bits sRGB values, linear light needs float intermediates
+    if linear {
+        LINEAR_RESIZES.fetch_add(1, Ordering::Relaxed);
+        return resize_float(image, width, height, true);
+    }
+
+    match resize_simd(image, width, height) {
+        Ok(resized) => {
+            SIMD_RESIZES.fetch_add(1, Ordering::Relaxed);
+            resized
+        },
+        Err(error) => {
+            warn!("SIMD resizing failed, falling back to the image crate: {}", error);
+            FALLBACK_RESIZES.fetch_add(1, Ordering::Relaxed);
+            resize_fallback(image, width, height)
+        }
+    }
+}
+
+/// Convolution resizer supporting RGB8 and RGBA8, alpha is premultiplied before resampling
+fn resize_simd(image: &DynamicImage, width: u32, height: u32) -> Result<DynamicImage, Error> {
+    let pixel_type = match image {
+        DynamicImage::ImageRgb8(_) => PixelType::U8x3,
+        DynamicImage::ImageRgba8(_) => PixelType::U8x4,
+        _ => return Error::err("Unsupported pixel format"),
+    };
+
+    let (width, height) = images::fit_dimensions(image.width(), image.height(), width, height);
+    let source = Image::from_vec_u8(image.width(), image.height(), image.as_bytes().to_vec(), pixel_type)?;
+    let mut destination = Image::new(width, height, pixel_type);
+
+    let options = ResizeOptions::new()
+        .resize_alg(ResizeAlg::Convolution(SimdFilterType::Lanczos3))
+        .use_alpha(true);
+
+    Resizer::new().resize(&source, &mut destination, &options)?;
+
+    let resized = match pixel_type {
+        PixelType::U8x3 => RgbImage::from_raw(width, height, destination.into_vec()).map(DynamicImage::ImageRgb8),
+        _ => RgbaImage::from_raw(width, height, destination.into_vec()).map(DynamicImage::ImageRgba8),
+    };
+
+    resized.ok_or_else(|| Error::new("Invalid resized pixel data"))
+}
+
+fn resize_fallback(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    match image {
+        DynamicImage::ImageRgba8(_) => resize_float(image, width, height, false),
+        _ => image.resize(width, height, FilterType::Lanczos3),
+    }
+}
+
+/// Resamples in floats, resampling straight alpha bleeds the color of transparent pixels
+/// into the edges so alpha is premultiplied, floats also keep the precision of faint pixels.
+/// In linear light, averaging is done on light intensities instead of sRGB values which
+/// would darken thin high contrast details
+fn resize_float(image: &DynamicImage, width: u32, height: u32, linear: bool) -> DynamicImage {
+    let source = image.to_rgba32f();
+    let decode = |channel: f32| if linear { srgb_to_linear(channel) } else { channel };
+    let encode = |channel: f32| if linear { linear_to_srgb(channel) } else { channel };
+
+    let premultiplied = ImageBuffer::from_fn(source.width(), source.height(), |x, y| {
+        let [r, g, b, a] = source.get_pixel(x, y).0;
+        Rgba([decode(r) * a, decode(g) * a, decode(b) * a, a])
+    });
+
+    let (width, height) = images::fit_dimensions(source.width(), source.height(), width, height);
+    let resized = image::imageops::resize(&premultiplied, width, height, FilterType::Lanczos3);
+
+    let resized = RgbaImage::from_fn(width, height, |x, y| {
+        let [r, g, b, a] = resized.get_pixel(x, y).0.map(|channel| channel.clamp(0.0, 1.0));
+        let unpremultiply = |channel: f32| if a > 0.0 { encode((channel / a).min(1.0)) } else { 0.0 };
+
+        Rgba([unpremultiply(r), unpremultiply(g), unpremultiply(b), a].map(|channel| (channel * 255.0).round() as u8))
+    });
+
+    if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(resized)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(resized).to_rgb8())
+    }
+}
+
+fn srgb_to_linear(channel: f32) -> f32 {
+    if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(channel: f32) -> f32 {
+    if channel <= 0.0031308 {
+        channel * 12.92
+    } else {
+        1.055 * channel.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cutout() -> DynamicImage {
+        //white product on a transparent background whose pixels are black
+        images::read(concat!(env!("CARGO_MANIFEST_DIR"), "/media/products/cutout.png"), None, None).expect("Failed to read fixture")
+    }
+
+    fn assert_no_halo(resized: &DynamicImage) {
+        let resized = resized.to_rgba8();
+        let edges = resized.pixels().filter(|pixel| pixel.0[3] > 0 && pixel.0[3] < 255).count();
+        assert!(edges > 0, "the fixture should produce semi transparent edges");
+
+        for pixel in resized.pixels().filter(|pixel| pixel.0[3] > 0) {
+            assert!(pixel.0[..3].iter().all(|channel| *channel >= 250), "dark halo pixel {:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn test_resize_premultiplies_alpha() {
+        assert_no_halo(&resize(&cutout(), 21, 21, false));
+        assert_no_halo(&resize(&cutout(), 21, 21, true));
+    }
+
+    #[test]
+    fn test_fallback_premultiplies_alpha() {
+        let resized = resize_fallback(&cutout(), 21, 21);
+
+        assert_eq!((resized.width(), resized.height()), (21, 21));
+        assert_no_halo(&resized);
+    }
+
+    #[test]
+    fn test_fallback_keeps_transparent_pixels_transparent() {
+        let resized = resize_fallback(&cutout(), 16, 16).to_rgba8();
+
+        assert_eq!(resized.get_pixel(0, 0).0, [0, 0, 0, 0]);
+        assert_eq!(resized.get_pixel(8, 8).0, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_resize_falls_back_for_other_pixel_formats() {
+        //read() only produces 8 bits RGB and RGBA, other pixel formats are left to the image crate
+        let image = DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(64, 32, image::Luma([40000u16])));
+        let fallbacks = FALLBACK_RESIZES.load(Ordering::Relaxed);
+
+        let resized = resize(&image, 16, 16, false);
+
+        assert_eq!((resized.width(), resized.height()), (16, 8));
+        assert!(matches!(resized, DynamicImage::ImageLuma16(_)));
+        assert!(FALLBACK_RESIZES.load(Ordering::Relaxed) > fallbacks);
+        assert!(implementation().contains(" fallback"));
+    }
+
+    #[test]
+    fn test_linear_resize_keeps_brightness() {
+        //one pixel wide black and white lines, half of the light is emitted
+        let lines = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, _| if x % 2 == 0 { image::Rgb([0, 0, 0]) } else { image::Rgb([255, 255, 255]) }));
+
+        let srgb = resize(&lines, 8, 8, false).to_rgb8();
+        let linear = resize(&lines, 8, 8, true);
+
+        assert!(matches!(linear, DynamicImage::ImageRgb8(_)));
+        assert!(srgb.get_pixel(4, 4).0[0] < 140);
+        //50% of the light is 188 in sRGB
+        assert!((185..=190).contains(&linear.to_rgb8().get_pixel(4, 4).0[0]));
+    }
+
+    #[test]
+    fn test_srgb_round_trip() {
+        for value in [0.0, 0.002, 0.2, 0.5, 1.0] {
+            assert!((linear_to_srgb(srgb_to_linear(value)) - value).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_resize_keeps_aspect_ratio() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(200, 100, image::Rgb([10, 20, 30])));
+
+        let resized = resize(&image, 50, 50, false);
+
+        assert_eq!((resized.width(), resized.height()), (50, 25));
+        assert!(matches!(resized, DynamicImage::ImageRgb8(_)));
+    }
+}
@@ -0,0 +1,62 @@
+use dssim_core::{Dssim, DssimImage};
+use image::DynamicImage;
+use rgb::FromSlice;
+use crate::error::Error;
+
+/// Compares encoded images to the image they were encoded from, the reference
+/// is only prepared once as it gets compared to every quality tried
+pub struct Similarity {
+    dssim: Dssim,
+    reference: DssimImage<f32>,
+}
+
+impl Similarity {
+    pub fn new(reference: &DynamicImage) -> Result<Self, Error> {
+        let dssim = Dssim::new();
+        let reference = to_dssim_image(&dssim, reference)?;
+
+        Ok(Similarity {
+            dssim,
+            reference,
+        })
+    }
+
+    /// 0 means identical, the higher the more different
+    pub fn dssim(&self, image: &DynamicImage) -> Result<f64, Error> {
+        let image = to_dssim_image(&self.dssim, image)?;
+        let (dssim, _) = self.dssim.compare(&self.reference, image);
+
+        Ok(dssim.into())
+    }
+}
+
+fn to_dssim_image(dssim: &Dssim, image: &DynamicImage) -> Result<DssimImage<f32>, Error> {
+    let pixels = image.to_rgba8();
+
+    dssim.create_image_rgba(pixels.as_raw().as_rgba(), pixels.width() as usize, pixels.height() as usize)
+        .ok_or_else(|| Error::new("Failed to prepare image for comparison"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn gradient(offset: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8 * 8, y as u8 * 8, offset])))
+    }
+
+    #[test]
+    fn test_identical_images() {
+        let similarity = Similarity::new(&gradient(0)).unwrap();
+
+        assert!(similarity.dssim(&gradient(0)).unwrap() < 0.0001);
+    }
+
+    #[test]
+    fn test_different_images() {
+        let similarity = Similarity::new(&gradient(0)).unwrap();
+
+        assert!(similarity.dssim(&gradient(200)).unwrap() > similarity.dssim(&gradient(10)).unwrap());
+    }
+}
@@ -0,0 +1,291 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+use image::{DynamicImage, RgbaImage};
+use resvg::tiny_skia::{IntSize, Pixmap, Transform};
+use resvg::usvg::{fontdb, ImageHrefResolver, Options, Tree};
+use roxmltree::{Attribute, Document, Node, ParsingOptions};
+use crate::error::Error;
+
+pub const EXTENSIONS: [&str; 1] = ["svg"];
+
+pub const MIME_TYPE: &str = "image/svg+xml";
+
+/// Elements running code or embedding other documents, removed along with their content
+const UNSAFE_ELEMENTS: [&str; 2] = ["script", "foreignObject"];
+
+/// Elements that can animate an attribute into a script or an external reference
+const ANIMATION_ELEMENTS: [&str; 4] = ["animate", "animateMotion", "animateTransform", "set"];
+
+/// Loading the system fonts scans every font file, it is only done once
+static FONTS: LazyLock<Arc<fontdb::Database>> = LazyLock::new(|| {
+    let mut fonts = fontdb::Database::new();
+    fonts.load_system_fonts();
+
+    Arc::new(fonts)
+});
+
+pub fn is_svg<T>(path: T) -> bool where T: AsRef<Path> {
+    path.as_ref()
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"))
+}
+
+/// Parses the SVG into the simplified tree it is rendered from. Scripts, event handlers and any
+/// other element that can not be rendered are dropped by the parser, only embedded `data:` images
+/// are resolved so rendering can not read external resources
+fn parse<T>(path: T) -> Result<Tree, Error> where T: AsRef<Path> {
+    let options = Options {
+        image_href_resolver: ImageHrefResolver {
+            resolve_data: ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|_, _| None),
+        },
+        fontdb: FONTS.clone(),
+        ..Options::default()
+    };
+
+    Ok(Tree::from_data(&fs::read(path)?, &options)?)
+}
+
+/// Removes scripts, event handlers and references to external resources, the rest of the markup is
+/// kept byte for byte. Only `data:` URLs and references to fragments of the document itself are kept,
+/// documents declaring entities are rejected as they would be expanded after sanitizing
+pub fn sanitize<T>(path: T) -> Result<String, Error> where T: AsRef<Path> {
+    let source = fs::read_to_string(path)?;
+    let document = Document::parse_with_options(&source, ParsingOptions { allow_dtd: true, ..ParsingOptions::default() })?;
+
+    let prolog = &source[..document.root_element().range().start];
+    if prolog.find("<!DOCTYPE").is_some_and(|start| prolog[start..].find(['[', '>']).is_some_and(|end| prolog[start + end..].starts_with('['))) {
+        return Error::err("SVGs with an internal DTD subset are not supported");
+    }
+
+    let mut edits = Vec::<(Range<usize>, String)>::new();
+
+    for node in document.descendants() {
+        if node.is_pi() || (node.is_element() && is_unsafe_element(node)) {
+            edits.push((node.range(), String::new()));
+            continue;
+        }
+
+        if node.is_element() && node.tag_name().name() == "style" {
+            if let Some(edit) = sanitize_style_element(&source, node) {
+                edits.push(edit);
+            }
+        }
+
+        for attribute in node.attributes() {
+            if is_unsafe_attribute(&attribute) {
+                edits.push((attribute.range(), String::new()));
+            } else if let Some(value) = strip_external_urls(attribute.value()) {
+                edits.push((attribute.range_value(), escape(&value)));
+            }
+        }
+    }
+
+    //the edits inside a removed element are skipped along with it
+    edits.sort_by_key(|(range, _)| range.start);
+    let mut sanitized = String::with_capacity(source.len());
+    let mut position = 0;
+
+    for (range, replacement) in edits {
+        if range.start < position {
+            continue;
+        }
+
+        sanitized.push_str(&source[position..range.start]);
+        sanitized.push_str(&replacement);
+        position = range.end;
+    }
+    sanitized.push_str(&source[position..]);
+
+    Ok(sanitized)
+}
+
+fn is_unsafe_element(node: Node) -> bool {
+    let name = node.tag_name().name();
+    let animates = |attribute: &str| attribute.eq_ignore_ascii_case("href") || attribute.ends_with(":href") || is_event_handler(attribute);
+
+    UNSAFE_ELEMENTS.contains(&name) || (ANIMATION_ELEMENTS.contains(&name) && node.attribute("attributeName").is_some_and(animates))
+}
+
+fn is_unsafe_attribute(attribute: &Attribute) -> bool {
+    is_event_handler(attribute.name()) || (attribute.name() == "href" && is_external(attribute.value()))
+}
+
+fn is_event_handler(name: &str) -> bool {
+    name.get(..2).is_some_and(|prefix| prefix.eq_ignore_ascii_case("on"))
+}
+
+fn is_external(reference: &str) -> bool {
+    let reference = reference.trim().trim_matches(['"', '\'']).trim();
+
+    !reference.is_empty()
+        && !reference.starts_with('#')
+        && !reference.get(..5).is_some_and(|scheme| scheme.eq_ignore_ascii_case("data:"))
+}
+
+/// Replaces the `url()` references to external resources by `none` and drops `@import` rules,
+/// None when there is nothing to strip
+fn strip_external_urls(css: &str) -> Option<String> {
+    let lowercase = css.to_ascii_lowercase();
+    let mut stripped = String::with_capacity(css.len());
+    let mut position = 0;
+
+    while let Some(start) = ["url(", "@import"].iter().filter_map(|token| lowercase[position..].find(token)).min().map(|start| position + start) {
+        stripped.push_str(&css[position..start]);
+
+        if lowercase[start..].starts_with("@import") {
+            position = lowercase[start..].find(';').map_or(css.len(), |end| start + end + 1);
+            continue;
+        }
+
+        let end = lowercase[start..].find(')').map_or(css.len(), |end| start + end + 1);
+        let reference = &css[start + 4..end.saturating_sub(1).max(start + 4)];
+        stripped.push_str(if is_external(reference) { "none" } else { &css[start..end] });
+        position = end;
+    }
+
+    if position == 0 {
+        return None;
+    }
+
+    stripped.push_str(&css[position..]);
+    Some(stripped)
+}
+
+/// Rewrites the content of a style element when it references external resources, as CDATA
+/// so the stylesheet does not need to be escaped
+fn sanitize_style_element(source: &str, node: Node) -> Option<(Range<usize>, String)> {
+    let css = node.children().filter_map(|child| child.text()).collect::<String>();
+    let stripped = strip_external_urls(&css)?;
+
+    let range = node.range();
+    let tag_end = node.attributes().next_back().map_or(range.start, |attribute| attribute.range().end);
+    let content_start = tag_end + source[tag_end..range.end].find('>')? + 1;
+    let content_end = range.start + source[range.clone()].rfind("</")?;
+
+    Some((content_start..content_end, format!("<![CDATA[{}]]>", stripped.replace("]]>", "]]]]><![CDATA[>"))))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+pub fn rasterize<T>(path: T, width: u32, height: u32) -> Result<DynamicImage, Error> where T: AsRef<Path> {
+    let tree = parse(path)?;
+    let bounds = IntSize::from_wh(width, height).ok_or_else(|| Error::new("Invalid rasterization size"))?;
+    let size = tree.size().to_int_size().scale_to(bounds);
+
+    let mut pixmap = Pixmap::new(size.width(), size.height()).ok_or_else(|| Error::new("Failed to allocate SVG pixmap"))?;
+    let transform = Transform::from_scale(
+        size.width() as f32 / tree.size().width(),
+        size.height() as f32 / tree.size().height(),
+    );
+
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    //tiny-skia works with premultiplied colors
+    let pixels = pixmap.pixels()
+        .iter()
+        .map(|pixel| pixel.demultiply())
+        .flat_map(|color| [color.red(), color.green(), color.blue(), color.alpha()])
+        .collect::<Vec<u8>>();
+
+    RgbaImage::from_raw(size.width(), size.height(), pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| Error::new("Invalid SVG pixel data"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::utils;
+
+    fn write_svg(name: &str, content: &str) -> PathBuf {
+        let path = PathBuf::from(utils::temp_dir(&format!("{}.svg", name)));
+        fs::write(&path, content).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn test_is_svg() {
+        assert!(is_svg("icons/logo.svg"));
+        assert!(is_svg("icons/LOGO.SVG"));
+        assert!(!is_svg("photo.jpeg"));
+    }
+
+    #[test]
+    fn test_sanitize_strips_scripts_and_external_references() {
+        let path = write_svg("sanitize", r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="100" height="100" onload="alert(1)">
+            <script>alert(2)</script>
+            <image width="10" height="10" xlink:href="https://example.com/tracker.png"/>
+            <image width="10" height="10" xlink:href="/etc/passwd"/>
+            <rect width="50" height="50" fill="red" style="fill: url(https://example.com/paint.svg#red)"/>
+            <a href="javascript:alert(3)"><text>link</text></a>
+            <set attributeName="href" to="javascript:alert(4)"/>
+            <style>@import url(https://example.com/fonts.css); rect { fill: url(#gradient) }</style>
+        </svg>"#);
+
+        let sanitized = sanitize(&path).expect("Failed to sanitize SVG");
+
+        assert!(!sanitized.contains("script"));
+        assert!(!sanitized.contains("alert"));
+        assert!(!sanitized.contains("example.com"));
+        assert!(!sanitized.contains("passwd"));
+        assert!(sanitized.contains(r#"<rect width="50" height="50" fill="red" style="fill: none"/>"#));
+        assert!(sanitized.contains("rect { fill: url(#gradient) }"));
+    }
+
+    #[test]
+    fn test_sanitize_keeps_markup() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 100" role="img" aria-labelledby="title">
+            <title id="title">Company logo</title>
+            <defs><linearGradient id="fade"><stop offset="0" stop-color="#fff"/></linearGradient></defs>
+            <circle cx="50" cy="50" r="40" fill="url(#fade)"><animate attributeName="r" values="40;45;40" dur="2s"/></circle>
+            <use href="#fade"/>
+            <image width="10" height="10" href="data:image/png;base64,iVBORw0KGgo="/>
+            <text font-family="Inter">ACME</text>
+        </svg>"##;
+
+        assert_eq!(sanitize(write_svg("keep", svg)).unwrap(), svg);
+    }
+
+    #[test]
+    fn test_sanitize_rejects_entities() {
+        let path = write_svg("entities", r#"<?xml version="1.0"?>
+            <!DOCTYPE svg [<!ENTITY script "<script>alert(1)</script>">]>
+            <svg xmlns="http://www.w3.org/2000/svg">&script;</svg>"#);
+
+        assert!(sanitize(&path).is_err());
+
+        //exported by most editors, it does not declare anything
+        let svg = r#"<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd">
+            <svg xmlns="http://www.w3.org/2000/svg"><rect width="5" height="5"/></svg>"#;
+        assert_eq!(sanitize(write_svg("doctype", svg)).unwrap(), svg);
+    }
+
+    #[test]
+    fn test_rasterize_fits_in_size() {
+        let path = write_svg("rasterize", r#"<svg xmlns="http://www.w3.org/2000/svg" width="200" height="100">
+            <rect width="200" height="100" fill="blue"/>
+        </svg>"#);
+
+        let image = rasterize(&path, 50, 50).expect("Failed to rasterize SVG");
+
+        assert_eq!(image.width(), 50);
+        assert_eq!(image.height(), 25);
+        assert_eq!(image.to_rgba8().get_pixel(10, 10).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_sanitize_invalid_svg() {
+        let path = write_svg("invalid", "<svg");
+
+        assert!(sanitize(&path).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use std::ffi::c_int;
 use image::DynamicImage;
-use webp::{Encoder, WebPConfig, WebPMemory};
+use webp::{Decoder, Encoder, WebPConfig, WebPMemory};
+use crate::config::WebpEncoder;
 use crate::error::Error;
 use crate::images::OptimizedImage;
 
@@ -12,6 +13,13 @@ impl OptimizedImage for Webp {
     fn data(&self) -> &[u8] {
         self.data.as_ref()
     }
+
+    fn decode(&self) -> Result<DynamicImage, Error> {
+        Decoder::new(self.data())
+            .decode()
+            .map(|image| image.to_image())
+            .ok_or_else(|| Error::new("Failed to decode webp"))
+    }
 }
 
 impl Into<Webp> for WebPMemory {
@@ -22,19 +30,33 @@ impl Into<Webp> for WebPMemory {
     }
 }
 
-pub fn to_webp(image: &DynamicImage, quality: f32, autofilter: bool) -> Result<Webp, Error> {
+pub fn to_webp(image: &DynamicImage, quality: f32, encoder: &WebpEncoder, lossless: bool, autofilter: bool) -> Result<Webp, Error> {
     let mut config = WebPConfig::new().map_err(|_| Error::new("Failed to create webp config"))?;
     config.quality = quality;
-    config.lossless = 0;
-    config.alpha_quality = 50;
+    config.lossless = lossless as c_int;
+    config.alpha_quality = encoder.alpha_quality.unwrap_or(50) as c_int;
     config.alpha_compression = 1;
     config.alpha_filtering = 0;
     config.autofilter = autofilter as c_int;
-    config.filter_sharpness = 4;
-    config.filter_strength = 50;
+    config.filter_sharpness = encoder.filter_sharpness.unwrap_or(4) as c_int;
+    config.filter_strength = encoder.filter_strength.unwrap_or(50) as c_int;
     config.filter_type = 0;
-    config.use_sharp_yuv = 0;
-    config.method = 3;
+    config.use_sharp_yuv = encoder.sharp_yuv.unwrap_or(false) as c_int;
+    config.method = encoder.method.unwrap_or(3) as c_int;
+
+    if let Some(sns_strength) = encoder.sns_strength {
+        config.sns_strength = sns_strength as c_int;
+    }
+
+    if let Some(segments) = encoder.segments {
+        config.segments = segments as c_int;
+    }
+
+    //near lossless is a preprocessing step of the lossless encoder
+    if let Some(near_lossless) = encoder.near_lossless {
+        config.lossless = 1;
+        config.near_lossless = near_lossless as c_int;
+    }
 
     Encoder::from_image(image)
         .expect("Unsupported format")
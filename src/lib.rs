@@ -17,18 +17,25 @@ use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender,Config as LogConfig, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use log::LevelFilter;
-use varnish::vcl::ctx::Ctx;
+use std::time::Duration;
+use varnish::vcl::ctx::{Ctx, Event};
+use varnish::vcl::http::HTTP;
+use varnish::vcl::vpriv::VPriv;
 use varnish::vcl::backend::{Backend, VCLBackendPtr};
 use crate::error::Error;
 use crate::backend::{FileBackend, FileTransfer};
 use crate::cache::Cache;
-use crate::config::{Config, Logger as LoggerConfig};
+use crate::config::{Config, ImageUrl, Logger as LoggerConfig};
 
 #[allow(non_camel_case_types)]
 type new = Impress;
 
+/// Time a discard waits for the background threads of the caches that were shut down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 struct Impress {
     backend: Backend<FileBackend, FileTransfer>,
+    config_path: Option<String>,
 }
 
 impl Impress {
@@ -38,17 +45,110 @@ impl Impress {
             setup_logging(logger);
         }
 
-        let cache = Cache::new(&config);
-        let backend = FileBackend::new(config, cache);
+        info!("Resizing images with {}", images::resizer());
+
+        let cache = cache::attach(config)?;
+        let backend = FileBackend::new(cache);
 
         let backend = Backend::new(ctx, vcl_name, backend, false)?;
 
-        Ok(Impress { backend })
+        Ok(Impress {
+            backend,
+            config_path: path.map(str::to_owned),
+        })
     }
 
     pub fn backend(&self, _ctx: &Ctx) -> VCLBackendPtr {
         self.backend.vcl_ptr()
     }
+
+    pub fn resizer(&self, _ctx: &Ctx) -> String {
+        images::resizer()
+    }
+
+    pub fn reload(&self, _ctx: &Ctx) -> Result<(), Error> {
+        let config = Config::open(self.config_path.as_deref())?;
+        self.cache().reload(config)
+    }
+
+    pub fn is_image_url(&self, ctx: &Ctx, url: Option<&str>) -> Result<bool, Error> {
+        Ok(self.parse_url(ctx, url)?.is_some())
+    }
+
+    pub fn size_of(&self, ctx: &Ctx, url: Option<&str>) -> Result<Option<String>, Error> {
+        Ok(self.parse_url(ctx, url)?.map(|image| image.size))
+    }
+
+    pub fn format_for(&self, ctx: &Ctx, url: Option<&str>) -> Result<Option<String>, Error> {
+        let Some(image) = self.parse_url(ctx, url)? else {
+            return Ok(None);
+        };
+
+        let accept = request(ctx).and_then(|req| self.backend.get_inner().parse_accept_header(req));
+        let extension = self.cache().negotiate(&image.path, &image.size, image.root.as_deref(), accept.as_ref())?;
+
+        Ok(extension.map(|extension| extension.name().to_owned()))
+    }
+
+    pub fn accept_key(&self, ctx: &Ctx) -> Result<String, Error> {
+        let accept = request(ctx).and_then(|req| self.backend.get_inner().parse_accept_header(req));
+
+        Ok(self.cache().accept_key(accept.as_ref())?.name().to_owned())
+    }
+
+    pub fn url(&self, _ctx: &Ctx, path: &str, size: &str, ext: Option<&str>) -> Result<String, Error> {
+        self.cache().config()?.build_url(path, size, ext)
+    }
+
+    pub fn srcset(&self, _ctx: &Ctx, path: &str, sizes: &str) -> Result<String, Error> {
+        let cache = self.cache();
+        cache.config()?.build_srcset(path, sizes, |root| cache.dimensions(path, root))
+    }
+
+    pub fn prewarm(&self, _ctx: &Ctx, image_id: &str, size: Option<&str>) -> Result<(), Error> {
+        self.cache().prewarm(image_id, size)
+    }
+
+    pub fn purge(&self, _ctx: &Ctx, image_id: &str, reoptimize: Option<bool>) -> Result<(), Error> {
+        self.cache().purge(image_id, reoptimize.unwrap_or(false))
+    }
+
+    pub fn purge_size(&self, _ctx: &Ctx, size: &str, reoptimize: Option<bool>) -> Result<(), Error> {
+        self.cache().purge_size(size, reoptimize.unwrap_or(false))
+    }
+
+    pub fn purge_all(&self, _ctx: &Ctx, reoptimize: Option<bool>) -> Result<(), Error> {
+        self.cache().purge_all(reoptimize.unwrap_or(false))
+    }
+
+    /// Parses the given URL or the URL of the current request
+    fn parse_url(&self, ctx: &Ctx, url: Option<&str>) -> Result<Option<ImageUrl>, Error> {
+        let url = match url {
+            Some(url) => url,
+            None => request(ctx).and_then(HTTP::url).ok_or_else(|| Error::new("Failed to get URL"))?,
+        };
+
+        self.cache().config()?.parse_url(url)
+    }
+
+    fn cache(&self) -> &Cache {
+        self.backend.get_inner().cache()
+    }
+}
+
+/// Objects are dropped before the discard event, which shuts their cache down when no other
+/// VCL uses it, the discard then waits for the background threads to exit
+pub fn event(_ctx: &mut Ctx, _vp: &mut VPriv<()>, event: Event) -> Result<(), Error> {
+    if let Event::Discard = event {
+        cache::reap(SHUTDOWN_TIMEOUT)?;
+    }
+
+    Ok(())
+}
+
+/// Client request in client subroutines, backend request in backend subroutines
+fn request<'a>(ctx: &'a Ctx) -> Option<&'a HTTP<'a>> {
+    ctx.http_req.as_ref().or(ctx.http_bereq.as_ref())
 }
 
 fn setup_logging(logger_config: &LoggerConfig) {
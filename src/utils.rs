@@ -13,6 +13,12 @@ pub fn decompose_filename(path: &str) -> (Option<&str>, Option<&str>) {
     (stem, extension)
 }
 
+/// Directory of a test under the system temporary directory
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> String {
+    std::env::temp_dir().join(format!("impress_{}", name)).to_string_lossy().to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;